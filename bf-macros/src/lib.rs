@@ -0,0 +1,78 @@
+//! The [`bf!`] macro, which compiles Brainfuck programs into Rust functions
+//! at build time.
+
+use std::io;
+
+use bf::bf::codegen::rust;
+use bf::bf::VirtualMachine;
+use proc_macro2::{Span, TokenStream};
+
+/// Compiles a Brainfuck program into a function of type
+/// `fn(&mut dyn Read, &mut dyn Write) -> io::Result<()>`, using the default
+/// configuration of [`VirtualMachine::new`].
+///
+/// The program is either a string literal or written out as tokens. Brackets
+/// in tokens must balance for Rust to accept them, so programs that may not
+/// should be given as strings; unmatched brackets are then reported at the
+/// call site.
+///
+/// ```
+/// use bf_macros::bf;
+///
+/// let mut output = Vec::new();
+/// bf!(++++++++[>++++++++<-]>+.)(&mut std::io::empty(), &mut output).unwrap();
+/// assert_eq!(output, b"A");
+/// ```
+///
+/// ```compile_fail
+/// // error: unmatched '[' at line 1, column 2
+/// let program = bf_macros::bf!("+[");
+/// ```
+#[proc_macro]
+pub fn bf(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    expand(input.into()).into()
+}
+
+fn expand(input: TokenStream) -> TokenStream {
+    let (code, span) = match syn::parse2::<syn::LitStr>(input.clone()) {
+        Ok(literal) => (literal.value(), literal.span()),
+        Err(_) => (input.to_string(), Span::call_site()),
+    };
+    let mut vm = VirtualMachine::new(io::empty(), io::sink());
+    if let Err(err) = vm.compile(&code) {
+        return syn::Error::new(span, err).to_compile_error();
+    }
+    let function = rust::generate(&vm, "program").expect("8-bit cells are supported");
+    format!("{{ {} program }}", function)
+        .parse()
+        .expect("generated code is valid Rust")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_str(input: &str) -> String {
+        expand(input.parse().unwrap()).to_string()
+    }
+
+    #[test]
+    fn test_expand() {
+        let expanded = expand_str("+[>+<-]");
+        assert!(expanded.starts_with("{ # [allow (unused , clippy :: all)] fn program"));
+        assert!(expanded.ends_with("program }"));
+        assert_eq!(expanded, expand_str("\"+[>+<-]\""));
+    }
+
+    #[test]
+    fn test_unmatched() {
+        assert_eq!(
+            expand_str("\"+[\""),
+            ":: core :: compile_error ! { \"unmatched '[' at line 1, column 2\" }"
+        );
+        assert_eq!(
+            expand_str("\"\n+]\""),
+            ":: core :: compile_error ! { \"unmatched ']' at line 2, column 2\" }"
+        );
+    }
+}
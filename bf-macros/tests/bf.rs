@@ -0,0 +1,29 @@
+use std::io;
+
+use bf_macros::bf;
+
+#[test]
+fn test_tokens() {
+    let mut output = Vec::new();
+    let hello = bf! {
+        ++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>
+        ---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.
+    };
+    hello(&mut io::empty(), &mut output).unwrap();
+    assert_eq!(output, b"Hello World!\n");
+}
+
+#[test]
+fn test_string() {
+    let mut output = Vec::new();
+    let cat = bf!(",[.[-],]");
+    cat(&mut &b"cat"[..], &mut output).unwrap();
+    assert_eq!(output, b"cat");
+}
+
+#[test]
+fn test_wrap() {
+    let mut output = Vec::new();
+    bf!("<+.[>]")(&mut io::empty(), &mut output).unwrap();
+    assert_eq!(output, [1]);
+}
@@ -1,120 +1,948 @@
-use std::io::{Read, Write};
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-const MEMORY_SIZE: usize = 30000;
+#[cfg(feature = "async")]
+mod asynchronous;
+mod cell;
+pub mod codegen;
+#[cfg(test)]
+mod corpus;
+#[cfg(feature = "cranelift")]
+mod cranelift;
+mod debug;
+mod feed;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+mod jit;
+#[cfg(any(feature = "jit", feature = "cranelift"))]
+mod native;
+mod optimize;
+mod profile;
+mod snapshot;
+mod x86;
 
-#[derive(Debug)]
-enum Instruction {
-    Increment,
-    Decrement,
-    MoveRight,
-    MoveLeft,
-    Read,
-    Write,
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncVirtualMachine;
+pub use cell::{Arithmetic, Cell};
+pub use debug::{Breakpoint, Stop};
+pub use feed::{InputQueue, Status};
+pub use profile::Profile;
+pub use snapshot::{InvalidState, VmState};
+
+/// Default number of cells on the tape.
+pub const MEMORY_SIZE: usize = 30000;
+
+/// Number of steps between checks of the cancellation token.
+const CANCEL_INTERVAL: u64 = 1 << 14;
+
+/// A compiled instruction. Offsets address the cell that many cells away from
+/// the pointer, and loop instructions hold the index of their partner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// Add `delta` to a cell.
+    Add {
+        offset: isize,
+        delta: i32,
+    },
+    /// Move the pointer, right for positive values.
+    Move(isize),
+    /// Read a byte into a cell, from `,`.
+    Input {
+        offset: isize,
+    },
+    /// Write a cell as a byte, from `.`.
+    Output {
+        offset: isize,
+    },
     LoopStart(usize),
     LoopEnd(usize),
+    /// Zero a cell, from `[-]`.
+    SetZero {
+        offset: isize,
+    },
+    /// Add the current cell times `factor` to the cell at `offset`, unless the
+    /// current cell is zero. Emitted before a `SetZero` for loops like `[->++<]`.
+    MulAdd {
+        offset: isize,
+        factor: i32,
+    },
+    /// Move right in steps of the given stride until reaching a zero cell,
+    /// from `[>]`.
+    ScanRight(usize),
+    /// Move left in steps of the given stride until reaching a zero cell,
+    /// from `[<]`.
+    ScanLeft(usize),
 }
 
-struct VirtualMachine<R: Read, W: Write> {
-    memory: [u8; MEMORY_SIZE],
-    pointer: usize,
-    instructions: Vec<Instruction>,
-    input: R,
-    output: W,
+/// An error found while compiling a program, with the byte offset, line and
+/// column (both 1-based) of the offending bracket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    /// A `[` that is never closed.
+    UnmatchedOpen {
+        offset: usize,
+        line: usize,
+        column: usize,
+    },
+    /// A `]` without a preceding `[`.
+    UnmatchedClose {
+        offset: usize,
+        line: usize,
+        column: usize,
+    },
 }
 
-impl<R: Read, W: Write> VirtualMachine<R, W> {
-    fn new(input: R, output: W) -> VirtualMachine<R, W> {
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::UnmatchedOpen { line, column, .. } => {
+                write!(f, "unmatched '[' at line {}, column {}", line, column)
+            }
+            CompileError::UnmatchedClose { line, column, .. } => {
+                write!(f, "unmatched ']' at line {}, column {}", line, column)
+            }
+        }
+    }
+}
+
+impl error::Error for CompileError {}
+
+/// The reason a program stopped with a [`RuntimeError`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RuntimeErrorKind {
+    /// Reading a byte for `,` failed.
+    Read(io::Error),
+    /// Writing a byte for `.` failed.
+    Write(io::Error),
+    /// The pointer left the tape while wrapping is disabled.
+    PointerOutOfBounds,
+    /// The program ran for more steps than it was allowed to.
+    StepLimitExceeded,
+    /// `,` hit the end of input under [`EofPolicy::Error`].
+    UnexpectedEof,
+    /// A cell overflowed under [`Arithmetic::Checked`].
+    CellOverflow,
+    /// The cancellation token was set while the program ran.
+    Cancelled,
+}
+
+/// An error raised while running a program, with the instruction index and
+/// tape pointer at the point of failure.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub index: usize,
+    pub pointer: usize,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            RuntimeErrorKind::Read(err) => write!(f, "failed to read input: {}", err)?,
+            RuntimeErrorKind::Write(err) => write!(f, "failed to write output: {}", err)?,
+            RuntimeErrorKind::PointerOutOfBounds => write!(f, "pointer out of bounds")?,
+            RuntimeErrorKind::StepLimitExceeded => write!(f, "step limit exceeded")?,
+            RuntimeErrorKind::UnexpectedEof => write!(f, "unexpected end of input")?,
+            RuntimeErrorKind::CellOverflow => write!(f, "cell overflow")?,
+            RuntimeErrorKind::Cancelled => write!(f, "cancelled")?,
+        }
+        write!(f, " (instruction {}, pointer {})", self.index, self.pointer)
+    }
+}
+
+impl error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.kind {
+            RuntimeErrorKind::Read(err) | RuntimeErrorKind::Write(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Either kind of error [`execute`] can fail with.
+#[derive(Debug)]
+pub enum Error {
+    Compile(CompileError),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Compile(err) => err.fmt(f),
+            Error::Runtime(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Compile(err) => Some(err),
+            Error::Runtime(err) => Some(err),
+        }
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(err: CompileError) -> Error {
+        Error::Compile(err)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(err: RuntimeError) -> Error {
+        Error::Runtime(err)
+    }
+}
+
+/// Counters collected while running a program.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExecutionStats {
+    /// Number of instructions executed, with each move of a scan like `[>]`
+    /// counted as one more.
+    pub steps: u64,
+    /// Number of bytes consumed by `,`.
+    pub bytes_read: u64,
+    /// Number of bytes produced by `.`.
+    pub bytes_written: u64,
+}
+
+/// What `,` stores in the current cell once the input is exhausted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EofPolicy {
+    /// Leave the cell as it was.
+    #[default]
+    Unchanged,
+    /// Store 0.
+    Zero,
+    /// Store -1, i.e. the maximum value for unsigned cells.
+    MinusOne,
+    /// Stop with [`RuntimeErrorKind::UnexpectedEof`].
+    Error,
+}
+
+/// What happens when the pointer moves past either end of the tape.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PointerMode {
+    /// Wrap around to the other end of the tape.
+    #[default]
+    Wrap,
+    /// Stop with [`RuntimeErrorKind::PointerOutOfBounds`].
+    Error,
+    /// Grow the tape when moving past its right end. Moving left of the first
+    /// cell is still an error.
+    Extend,
+}
+
+/// How [`VirtualMachine::run`] executes compiled programs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Backend {
+    #[default]
+    Interpreter,
+    /// Native x86-64 code, for 8-bit cells with wrapping arithmetic on x86-64
+    /// Linux. Other configurations and targets use the interpreter. Native
+    /// code does not count [`ExecutionStats::steps`].
+    #[cfg(feature = "jit")]
+    Jit,
+    /// Native code compiled with Cranelift, for 8-bit cells with wrapping
+    /// arithmetic on any target Cranelift supports. Other configurations use
+    /// the interpreter, and steps are not counted either.
+    #[cfg(feature = "cranelift")]
+    Cranelift,
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    tape_len: usize,
+    pointer_mode: PointerMode,
+    eof_policy: EofPolicy,
+    arithmetic: Arithmetic,
+    optimize: bool,
+    partial_eval: Option<u64>,
+    backend: Backend,
+    step_limit: Option<u64>,
+    cancel: Option<Arc<AtomicBool>>,
+    profile: bool,
+    #[cfg(feature = "async")]
+    yield_interval: u64,
+}
+
+/// Configures and builds a [`VirtualMachine`] with cells of type `C`.
+#[derive(Debug, Clone)]
+pub struct VmBuilder<C: Cell = u8> {
+    config: Config,
+    cell: PhantomData<C>,
+}
+
+impl Default for VmBuilder {
+    fn default() -> VmBuilder {
+        VmBuilder::new()
+    }
+}
+
+impl VmBuilder {
+    /// Creates a builder for 8-bit cells.
+    pub fn new() -> VmBuilder {
+        VmBuilder {
+            config: Config {
+                tape_len: MEMORY_SIZE,
+                pointer_mode: PointerMode::default(),
+                eof_policy: EofPolicy::default(),
+                arithmetic: Arithmetic::default(),
+                optimize: true,
+                partial_eval: None,
+                backend: Backend::default(),
+                step_limit: None,
+                cancel: None,
+                profile: false,
+                #[cfg(feature = "async")]
+                yield_interval: asynchronous::YIELD_INTERVAL,
+            },
+            cell: PhantomData,
+        }
+    }
+}
+
+impl<C: Cell> VmBuilder<C> {
+    /// Switches the cell type, e.g. `.cell::<u32>()`.
+    pub fn cell<D: Cell>(self) -> VmBuilder<D> {
+        VmBuilder {
+            config: self.config,
+            cell: PhantomData,
+        }
+    }
+
+    /// Sets the initial number of cells, [`MEMORY_SIZE`] by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tape_len` is zero.
+    pub fn tape_len(mut self, tape_len: usize) -> VmBuilder<C> {
+        assert!(tape_len > 0, "tape length must be positive");
+        self.config.tape_len = tape_len;
+        self
+    }
+
+    pub fn pointer_mode(mut self, pointer_mode: PointerMode) -> VmBuilder<C> {
+        self.config.pointer_mode = pointer_mode;
+        self
+    }
+
+    pub fn eof_policy(mut self, eof_policy: EofPolicy) -> VmBuilder<C> {
+        self.config.eof_policy = eof_policy;
+        self
+    }
+
+    pub fn arithmetic(mut self, arithmetic: Arithmetic) -> VmBuilder<C> {
+        self.config.arithmetic = arithmetic;
+        self
+    }
+
+    /// Enables the optimization passes run by [`VirtualMachine::compile`],
+    /// on by default. Programs compiled with [`VirtualMachine::compile`] are
+    /// optimized for the zeroed tape it leaves behind. Optimized programs no
+    /// longer know their source positions, so [`Breakpoint::Source`] and `#`
+    /// only work without optimization.
+    pub fn optimize(mut self, optimize: bool) -> VmBuilder<C> {
+        self.config.optimize = optimize;
+        self
+    }
+
+    /// Evaluates the start of each program at compile time, up to its first
+    /// `,` or for at most `budget` steps, so that running it resumes from the
+    /// resulting tape after writing the output produced so far. Off by
+    /// default.
+    pub fn partial_eval(mut self, budget: Option<u64>) -> VmBuilder<C> {
+        self.config.partial_eval = budget;
+        self
+    }
+
+    pub fn backend(mut self, backend: Backend) -> VmBuilder<C> {
+        self.config.backend = backend;
+        self
+    }
+
+    /// Stops [`VirtualMachine::run`] with [`RuntimeErrorKind::StepLimitExceeded`]
+    /// once it has run `limit` [steps](ExecutionStats::steps), not counting any
+    /// evaluated at compile time. Unlimited by default. Native backends cannot count
+    /// steps, so a limited program is always interpreted.
+    pub fn step_limit(mut self, limit: Option<u64>) -> VmBuilder<C> {
+        self.config.step_limit = limit;
+        self
+    }
+
+    /// Stops [`VirtualMachine::run`] with [`RuntimeErrorKind::Cancelled`]
+    /// soon after `token` is set, e.g. from another thread. The token is
+    /// checked every few thousand steps, but not while `,` waits for input.
+    /// Programs that can be cancelled are always interpreted.
+    pub fn cancel_token(mut self, token: Arc<AtomicBool>) -> VmBuilder<C> {
+        self.config.cancel = Some(token);
+        self
+    }
+
+    /// Counts how often each instruction runs, for
+    /// [`VirtualMachine::profile`]. Off by default. Profiled programs are
+    /// always interpreted, and instructions evaluated at compile time are not
+    /// counted. Source positions are only known without optimization.
+    pub fn profile(mut self, profile: bool) -> VmBuilder<C> {
+        self.config.profile = profile;
+        self
+    }
+
+    pub fn build<R: Read, W: Write>(self, input: R, output: W) -> VirtualMachine<R, W, C> {
         VirtualMachine {
-            memory: [0; MEMORY_SIZE],
+            memory: vec![C::default(); self.config.tape_len],
             pointer: 0,
             instructions: Vec::new(),
+            offsets: Vec::new(),
+            dumps: Vec::new(),
+            index: 0,
+            stats: ExecutionStats::default(),
+            profile: None,
+            prelude: None,
+            #[cfg(any(feature = "jit", feature = "cranelift"))]
+            native: None,
             input,
             output,
+            config: self.config,
         }
     }
+}
+
+/// The state after the input-independent start of a program, computed at
+/// compile time.
+#[derive(Debug, Clone)]
+struct Prelude<C> {
+    memory: Vec<C>,
+    pointer: usize,
+    /// Index of the first instruction that still has to run.
+    index: usize,
+    output: Vec<u8>,
+}
+
+/// A Brainfuck interpreter reading `,` from `input` and writing `.` to `output`.
+pub struct VirtualMachine<R: Read, W: Write, C: Cell = u8> {
+    memory: Vec<C>,
+    pointer: usize,
+    instructions: Vec<Instruction>,
+    /// Source positions of the instructions, if they were not optimized.
+    offsets: Vec<usize>,
+    /// Indices of the instructions that follow a `#`, if not optimized.
+    dumps: Vec<usize>,
+    /// Index of the next instruction to execute.
+    index: usize,
+    stats: ExecutionStats,
+    profile: Option<Profile>,
+    prelude: Option<Prelude<C>>,
+    /// Machine code for the program, from the configured backend.
+    #[cfg(any(feature = "jit", feature = "cranelift"))]
+    native: Option<native::Code>,
+    input: R,
+    output: W,
+    config: Config,
+}
+
+/// Translates `code` into instructions one-to-one, ignoring any characters
+/// that are not Brainfuck commands.
+pub fn parse(code: &str) -> Result<Vec<Instruction>, CompileError> {
+    parse_program(code).map(|program| program.instructions)
+}
+
+/// A parsed program, with the source positions used for debugging.
+struct Program {
+    instructions: Vec<Instruction>,
+    /// Byte offset in the source of each instruction.
+    offsets: Vec<usize>,
+    /// Indices of the instructions that follow a `#`, in order.
+    dumps: Vec<usize>,
+}
+
+fn parse_program(code: &str) -> Result<Program, CompileError> {
+    let mut instructions = Vec::new();
+    let mut offsets = Vec::new();
+    let mut dumps = Vec::new();
+    let mut left: Vec<(usize, usize, usize, usize)> = Vec::new();
+    let (mut line, mut column) = (1, 0);
+    for (offset, ch) in code.char_indices() {
+        if ch == '\n' {
+            line += 1;
+            column = 0;
+            continue;
+        }
+        column += 1;
+        match ch {
+            '>' => instructions.push(Instruction::Move(1)),
+            '<' => instructions.push(Instruction::Move(-1)),
+            '+' => instructions.push(Instruction::Add {
+                offset: 0,
+                delta: 1,
+            }),
+            '-' => instructions.push(Instruction::Add {
+                offset: 0,
+                delta: -1,
+            }),
+            '.' => instructions.push(Instruction::Output { offset: 0 }),
+            ',' => instructions.push(Instruction::Input { offset: 0 }),
+            '[' => {
+                left.push((instructions.len(), offset, line, column));
+                instructions.push(Instruction::LoopStart(0));
+            }
+            ']' => {
+                let (l, ..) = left.pop().ok_or(CompileError::UnmatchedClose {
+                    offset,
+                    line,
+                    column,
+                })?;
+                instructions[l] = Instruction::LoopStart(instructions.len());
+                instructions.push(Instruction::LoopEnd(l));
+            }
+            '#' if dumps.last() != Some(&instructions.len()) => dumps.push(instructions.len()),
+            _ => {}
+        }
+        offsets.resize(instructions.len(), offset);
+    }
+    match left.pop() {
+        Some((_, offset, line, column)) => Err(CompileError::UnmatchedOpen {
+            offset,
+            line,
+            column,
+        }),
+        None => Ok(Program {
+            instructions,
+            offsets,
+            dumps,
+        }),
+    }
+}
+
+impl<R: Read, W: Write> VirtualMachine<R, W> {
+    /// Creates a machine with 8-bit cells and the default configuration.
+    pub fn new(input: R, output: W) -> VirtualMachine<R, W> {
+        VmBuilder::new().build(input, output)
+    }
+
+    pub fn builder() -> VmBuilder {
+        VmBuilder::new()
+    }
+}
+
+impl<R: Read, W: Write, C: Cell> VirtualMachine<R, W, C> {
+    pub fn memory(&self) -> &[C] {
+        &self.memory
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
 
-    fn reset(&mut self) {
-        self.memory = [0; MEMORY_SIZE];
+    /// Zeroes the tape, restoring its initial length, and moves the pointer
+    /// back to the first cell.
+    pub fn reset(&mut self) {
+        self.memory.clear();
+        self.memory.resize(self.config.tape_len, C::default());
         self.pointer = 0;
     }
 
-    fn clear(&mut self) {
+    /// Resets the tape and discards the compiled program.
+    pub fn clear(&mut self) {
         self.reset();
         self.instructions.clear();
+        self.offsets.clear();
+        self.dumps.clear();
+        self.prelude = None;
+        #[cfg(any(feature = "jit", feature = "cranelift"))]
+        {
+            self.native = None;
+        }
     }
 
-    fn compile(&mut self, code: &str) {
+    /// Compiles `code`, replacing any previous program and resetting the tape.
+    pub fn compile(&mut self, code: &str) -> Result<(), CompileError> {
         self.clear();
-        let mut left: Vec<usize> = Vec::new();
-        for (_, ch) in code.chars().enumerate() {
-            match ch {
-                '>' => self.instructions.push(Instruction::MoveRight),
-                '<' => self.instructions.push(Instruction::MoveLeft),
-                '+' => self.instructions.push(Instruction::Increment),
-                '-' => self.instructions.push(Instruction::Decrement),
-                '.' => self.instructions.push(Instruction::Write),
-                ',' => self.instructions.push(Instruction::Read),
-                '[' => {
-                    left.push(self.instructions.len());
-                    self.instructions.push(Instruction::LoopStart(0));
-                }
-                ']' => {
-                    let l = left.pop().unwrap();
-                    self.instructions[l] = Instruction::LoopStart(self.instructions.len());
-                    self.instructions.push(Instruction::LoopEnd(l));
-                }
-                _ => {}
+        self.translate(code, true)?;
+        if let Some(budget) = self.config.partial_eval {
+            self.prelude = self.evaluate_prelude(budget);
+        }
+        #[cfg(any(feature = "jit", feature = "cranelift"))]
+        {
+            self.native = native::compile::<R, W, C>(&self.instructions, &self.config);
+        }
+        Ok(())
+    }
+
+    /// Compiles `code`, replacing any previous program but keeping the tape
+    /// and pointer, so that [`run`](Self::run) continues from wherever the
+    /// last program stopped. The program is optimized without assuming a
+    /// zeroed tape, and is not partially evaluated.
+    pub fn compile_keeping_tape(&mut self, code: &str) -> Result<(), CompileError> {
+        self.translate(code, false)?;
+        self.prelude = None;
+        #[cfg(any(feature = "jit", feature = "cranelift"))]
+        {
+            self.native = native::compile::<R, W, C>(&self.instructions, &self.config);
+        }
+        Ok(())
+    }
+
+    /// Parses `code` into the program to run next, optimizing it if
+    /// configured to for a tape that is either `zeroed` or left over from an
+    /// earlier program.
+    fn translate(&mut self, code: &str, zeroed: bool) -> Result<(), CompileError> {
+        let program = parse_program(code)?;
+        self.index = 0;
+        self.stats = ExecutionStats::default();
+        if !self.config.optimize {
+            self.instructions = program.instructions;
+            self.offsets = program.offsets;
+            self.dumps = program.dumps;
+            self.reset_profile();
+            return Ok(());
+        }
+        let target = optimize::Target {
+            wrapping: self.config.arithmetic == Arithmetic::Wrapping || C::BITS.is_none(),
+            bits: C::BITS,
+            pointer_wraps: self.config.pointer_mode == PointerMode::Wrap,
+            tape_len: self.config.tape_len,
+            zeroed,
+        };
+        self.instructions = optimize::optimize(&program.instructions, target);
+        self.offsets.clear();
+        self.dumps.clear();
+        self.reset_profile();
+        Ok(())
+    }
+
+    fn error(&self, kind: RuntimeErrorKind, index: usize) -> RuntimeError {
+        RuntimeError {
+            kind,
+            index,
+            pointer: self.pointer,
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0; 1];
+        loop {
+            match self.input.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(buf[0])),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
             }
         }
     }
 
-    fn run(&mut self) {
-        let mut index = 0;
-        let size = self.instructions.len();
-        while index < size {
-            let mut next = index + 1;
-            match self.instructions[index] {
-                Instruction::Increment => {
-                    self.memory[self.pointer] = self.memory[self.pointer].wrapping_add(1);
-                }
-                Instruction::Decrement => {
-                    self.memory[self.pointer] = self.memory[self.pointer].wrapping_sub(1);
-                }
-                Instruction::MoveRight => {
-                    self.pointer = (self.pointer + 1) % MEMORY_SIZE;
+    /// Resolves the cell `offset` cells away from the pointer, growing the tape
+    /// if the pointer mode allows it.
+    fn address(&mut self, offset: isize) -> Result<usize, RuntimeErrorKind> {
+        if offset == 0 {
+            return Ok(self.pointer);
+        }
+        let len = self.memory.len();
+        match self.config.pointer_mode {
+            PointerMode::Wrap => {
+                let offset = offset.rem_euclid(len as isize) as usize;
+                Ok((self.pointer + offset) % len)
+            }
+            PointerMode::Error | PointerMode::Extend => {
+                let target = self
+                    .pointer
+                    .checked_add_signed(offset)
+                    .ok_or(RuntimeErrorKind::PointerOutOfBounds)?;
+                if target >= len {
+                    if self.config.pointer_mode == PointerMode::Error {
+                        return Err(RuntimeErrorKind::PointerOutOfBounds);
+                    }
+                    self.memory.resize(target + 1, C::default());
                 }
-                Instruction::MoveLeft => {
-                    self.pointer = (self.pointer + MEMORY_SIZE - 1) % MEMORY_SIZE;
+                Ok(target)
+            }
+        }
+    }
+
+    fn move_pointer(&mut self, delta: isize) -> Result<(), RuntimeErrorKind> {
+        self.pointer = self.address(delta)?;
+        Ok(())
+    }
+
+    fn mul_add(&mut self, offset: isize, factor: i32) -> Result<(), RuntimeErrorKind> {
+        if self.memory[self.pointer].is_zero() {
+            return Ok(());
+        }
+        let target = self.address(offset)?;
+        self.memory[target] = self.memory[target]
+            .mul_add(
+                &self.memory[self.pointer],
+                factor.into(),
+                self.config.arithmetic,
+            )
+            .ok_or(RuntimeErrorKind::CellOverflow)?;
+        Ok(())
+    }
+
+    /// Moves the pointer by `stride` until it reaches a zero cell, making at
+    /// most `room` moves and taking them from it. Returns whether a zero cell
+    /// was reached.
+    fn scan(&mut self, stride: isize, room: &mut u64) -> Result<bool, RuntimeErrorKind> {
+        // Search the rest of the tape directly, then fall back to stepping so
+        // that running off either end behaves exactly like a `[>]` loop.
+        let len = self.memory.len();
+        let reach = usize::try_from(*room).map_or(len, |room| room.min(len));
+        if stride == 1 {
+            let end = len.min(self.pointer + reach + 1);
+            let moves = match C::find_zero(&self.memory[self.pointer..end]) {
+                Some(found) => found,
+                None => end - 1 - self.pointer,
+            };
+            self.pointer += moves;
+            *room -= moves as u64;
+        } else if stride == -1 {
+            let start = self.pointer.saturating_sub(reach);
+            let found = match C::rfind_zero(&self.memory[start..=self.pointer]) {
+                Some(found) => start + found,
+                None => start,
+            };
+            *room -= (self.pointer - found) as u64;
+            self.pointer = found;
+        }
+        while !self.memory[self.pointer].is_zero() {
+            if *room == 0 {
+                return Ok(false);
+            }
+            self.move_pointer(stride)?;
+            *room -= 1;
+        }
+        Ok(true)
+    }
+
+    fn add(&mut self, offset: isize, delta: i32) -> Result<(), RuntimeErrorKind> {
+        let target = self.address(offset)?;
+        let cell = &mut self.memory[target];
+        *cell = cell
+            .add(delta.into(), self.config.arithmetic)
+            .ok_or(RuntimeErrorKind::CellOverflow)?;
+        Ok(())
+    }
+
+    /// Handles `,`, returning whether a byte was read.
+    fn input(&mut self, offset: isize) -> Result<bool, RuntimeErrorKind> {
+        let target = self.address(offset)?;
+        match self.read_byte().map_err(RuntimeErrorKind::Read)? {
+            Some(byte) => {
+                self.memory[target] = C::from_byte(byte);
+                return Ok(true);
+            }
+            None => match self.config.eof_policy {
+                EofPolicy::Unchanged => {}
+                EofPolicy::Zero => self.memory[target] = C::default(),
+                EofPolicy::MinusOne => self.memory[target] = C::minus_one(),
+                EofPolicy::Error => return Err(RuntimeErrorKind::UnexpectedEof),
+            },
+        }
+        Ok(false)
+    }
+
+    fn output(&mut self, offset: isize) -> Result<(), RuntimeErrorKind> {
+        let target = self.address(offset)?;
+        let buf = [self.memory[target].to_byte()];
+        self.output.write_all(&buf).map_err(RuntimeErrorKind::Write)
+    }
+
+    /// Runs the compiled program from its first instruction.
+    pub fn run(&mut self) -> Result<ExecutionStats, RuntimeError> {
+        self.start()?;
+        #[cfg(any(feature = "jit", feature = "cranelift"))]
+        if let (0, Some(code)) = (self.index, &self.native) {
+            // Native code only starts at the beginning of the program.
+            let entry = code.entry;
+            self.run_native(entry)?;
+        }
+        self.execute(None, false)?;
+        Ok(self.stats)
+    }
+
+    /// Runs the program from wherever it stopped until it ends, e.g. after
+    /// [`restore`](Self::restore) or the debugging methods. The program is
+    /// always interpreted.
+    pub fn resume(&mut self) -> Result<ExecutionStats, RuntimeError> {
+        self.execute(None, false)?;
+        Ok(self.stats)
+    }
+
+    /// Moves to the first instruction, or past the prelude if there is one.
+    fn start(&mut self) -> Result<(), RuntimeError> {
+        self.index = 0;
+        self.stats = ExecutionStats::default();
+        self.reset_profile();
+        if let Some(prelude) = &self.prelude {
+            self.memory.clone_from(&prelude.memory);
+            self.pointer = prelude.pointer;
+            self.index = prelude.index;
+            self.stats.bytes_written = prelude.output.len() as u64;
+            if let Err(err) = self.output.write_all(&prelude.output) {
+                return Err(self.error(RuntimeErrorKind::Write(err), self.index));
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes instructions from the current index until the program ends,
+    /// returning whether it did. Stops early once `budget` steps have run or,
+    /// if `stop_before_input` is set, when the next instruction is `,`.
+    ///
+    /// Each move of a scan counts as a step on top of the scan itself, so that
+    /// a scan that never finds a zero cell still runs into the limits.
+    fn execute(
+        &mut self,
+        budget: Option<u64>,
+        stop_before_input: bool,
+    ) -> Result<bool, RuntimeError> {
+        let end = budget.map_or(u64::MAX, |budget| self.stats.steps.saturating_add(budget));
+        // Limits are checked whenever the step count reaches the checkpoint.
+        let mut checkpoint = self.stats.steps;
+        while let Some(&instruction) = self.instructions.get(self.index) {
+            if self.stats.steps >= end
+                || (stop_before_input && matches!(instruction, Instruction::Input { .. }))
+            {
+                return Ok(false);
+            }
+            if self.stats.steps >= checkpoint {
+                checkpoint = self.check_limits()?;
+            }
+            let available = checkpoint.min(end) - self.stats.steps;
+            let mut room = available;
+            self.index = self
+                .apply_profiled(instruction, &mut room)
+                .map_err(|kind| self.error(kind, self.index))?;
+            self.stats.steps += 1 + (available - room);
+        }
+        Ok(true)
+    }
+
+    /// Fails if the step limit is reached or the program was cancelled, and
+    /// otherwise returns the step count at which to check again.
+    fn check_limits(&self) -> Result<u64, RuntimeError> {
+        let steps = self.stats.steps;
+        if self.config.step_limit.is_some_and(|limit| steps >= limit) {
+            return Err(self.error(RuntimeErrorKind::StepLimitExceeded, self.index));
+        }
+        let limit = self.config.step_limit.unwrap_or(u64::MAX);
+        match &self.config.cancel {
+            Some(token) if token.load(Ordering::Relaxed) => {
+                Err(self.error(RuntimeErrorKind::Cancelled, self.index))
+            }
+            Some(_) => Ok(limit.min(steps.saturating_add(CANCEL_INTERVAL))),
+            None => Ok(limit),
+        }
+    }
+
+    /// Executes `instruction`, returning the index of the next one. Scans move
+    /// the pointer at most `room` times, taking the moves from it, and return
+    /// their own index if they have to go on.
+    fn apply(
+        &mut self,
+        instruction: Instruction,
+        room: &mut u64,
+    ) -> Result<usize, RuntimeErrorKind> {
+        match instruction {
+            Instruction::Add { offset, delta } => self.add(offset, delta)?,
+            Instruction::Move(delta) => self.move_pointer(delta)?,
+            Instruction::Input { offset } => {
+                if self.input(offset)? {
+                    self.stats.bytes_read += 1;
                 }
-                Instruction::Read => {
-                    let mut buf = [0; 1];
-                    self.input.read_exact(&mut buf).unwrap();
-                    self.memory[self.pointer] = buf[0];
+            }
+            Instruction::Output { offset } => {
+                self.output(offset)?;
+                self.stats.bytes_written += 1;
+            }
+            Instruction::LoopStart(end) => {
+                if self.memory[self.pointer].is_zero() {
+                    return Ok(end + 1);
                 }
-                Instruction::Write => {
-                    let buf = [self.memory[self.pointer]];
-                    self.output.write_all(&buf).unwrap();
+            }
+            Instruction::LoopEnd(start) => {
+                if !self.memory[self.pointer].is_zero() {
+                    return Ok(start + 1);
                 }
-                Instruction::LoopStart(jump_to) => {
-                    if self.memory[self.pointer] == 0 {
-                        next = jump_to;
-                    }
+            }
+            Instruction::SetZero { offset } => {
+                let target = self.address(offset)?;
+                self.memory[target] = C::default();
+            }
+            Instruction::MulAdd { offset, factor } => self.mul_add(offset, factor)?,
+            Instruction::ScanRight(stride) => {
+                if !self.scan(stride as isize, room)? {
+                    return Ok(self.index);
                 }
-                Instruction::LoopEnd(jump_to) => {
-                    if self.memory[self.pointer] != 0 {
-                        next = jump_to;
-                    }
+            }
+            Instruction::ScanLeft(stride) => {
+                if !self.scan(-(stride as isize), room)? {
+                    return Ok(self.index);
                 }
             }
-            index = next;
         }
+        Ok(self.index + 1)
+    }
+
+    /// Runs the start of the program that does not depend on input, for at
+    /// most `budget` steps, and records the state it leaves behind.
+    fn evaluate_prelude(&self, budget: u64) -> Option<Prelude<C>> {
+        let mut vm = VirtualMachine {
+            memory: self.memory.clone(),
+            pointer: 0,
+            instructions: self.instructions.clone(),
+            offsets: Vec::new(),
+            dumps: Vec::new(),
+            index: 0,
+            stats: ExecutionStats::default(),
+            profile: None,
+            prelude: None,
+            #[cfg(any(feature = "jit", feature = "cranelift"))]
+            native: None,
+            input: io::empty(),
+            output: Vec::new(),
+            config: self.config.clone(),
+        };
+        // Errors are left for the real run to report.
+        vm.execute(Some(budget), true).ok()?;
+        Some(Prelude {
+            memory: vm.memory,
+            pointer: vm.pointer,
+            index: vm.index,
+            output: vm.output,
+        })
     }
 }
 
-pub fn execute<R: Read, W: Write>(code: &str, input: R, output: W) {
-    let mut vm = VirtualMachine::new(input, output);
-    vm.compile(code);
-    vm.run();
+pub fn execute<R: Read, W: Write>(
+    code: &str,
+    input: R,
+    output: W,
+) -> Result<ExecutionStats, Error> {
+    execute_with_eof_policy(code, input, output, EofPolicy::default())
+}
+
+pub fn execute_with_eof_policy<R: Read, W: Write>(
+    code: &str,
+    input: R,
+    output: W,
+    eof_policy: EofPolicy,
+) -> Result<ExecutionStats, Error> {
+    let mut vm = VmBuilder::new().eof_policy(eof_policy).build(input, output);
+    vm.compile(code)?;
+    Ok(vm.run()?)
 }
 
 #[cfg(test)]
@@ -125,7 +953,7 @@ mod tests {
     #[test]
     fn test_output() {
         let mut buffer = Cursor::new(vec![0u8; 1]);
-        execute(".", &mut io::empty(), &mut buffer);
+        execute(".", &mut io::empty(), &mut buffer).unwrap();
         assert_eq!(buffer.get_ref(), &vec![0u8]);
     }
 
@@ -133,42 +961,42 @@ mod tests {
     fn test_input() {
         let mut input = Cursor::new("A".as_bytes().to_vec());
         let mut output = Cursor::new(vec![0u8; 1]);
-        execute(",.", &mut input, &mut output);
+        execute(",.", &mut input, &mut output).unwrap();
         assert_eq!(input.get_ref(), &"A".as_bytes().to_vec());
     }
 
     #[test]
     fn test_move_right() {
         let mut buffer = Cursor::new(vec![0u8; 1]);
-        execute(">.", &mut io::empty(), &mut buffer);
+        execute(">.", &mut io::empty(), &mut buffer).unwrap();
         assert_eq!(buffer.get_ref(), &vec![0u8]);
     }
 
     #[test]
     fn test_move_left() {
         let mut buffer = Cursor::new(vec![0u8; 2]);
-        execute("+><.", &mut io::empty(), &mut buffer);
+        execute("+><.", &mut io::empty(), &mut buffer).unwrap();
         assert_eq!(buffer.get_ref(), &vec![1u8, 0u8]);
     }
 
     #[test]
     fn test_increment() {
         let mut buffer = Cursor::new(vec![0u8; 1]);
-        execute("+.", &mut io::empty(), &mut buffer);
+        execute("+.", &mut io::empty(), &mut buffer).unwrap();
         assert_eq!(buffer.get_ref(), &vec![1u8]);
     }
 
     #[test]
     fn test_decrement() {
         let mut buffer = Cursor::new(vec![0u8; 1]);
-        execute("+-.", &mut io::empty(), &mut buffer);
+        execute("+-.", &mut io::empty(), &mut buffer).unwrap();
         assert_eq!(buffer.get_ref(), &vec![0u8]);
     }
 
     #[test]
     fn test_loop() {
         let mut buffer = Cursor::new(vec![0u8; 1]);
-        execute("++[>+<-]>.", &mut io::empty(), &mut buffer);
+        execute("++[>+<-]>.", &mut io::empty(), &mut buffer).unwrap();
         assert_eq!(buffer.get_ref(), &vec![2u8]);
     }
 
@@ -179,7 +1007,8 @@ mod tests {
             "++++++ [ > ++++++++++ < - ] > +++++ .",
             &mut io::empty(),
             &mut buffer,
-        );
+        )
+        .unwrap();
         assert_eq!(buffer.get_ref(), &b"A"[..]);
     }
 
@@ -188,7 +1017,469 @@ mod tests {
         let mut input = Cursor::new(vec![30u8, 35u8]);
         let mut output = Cursor::new(vec![0u8; 1]);
         let code = ",>,<[- >+ <]>.";
-        execute(code, &mut input, &mut output);
+        execute(code, &mut input, &mut output).unwrap();
         assert_eq!(output.get_ref(), &b"A"[..]);
     }
+
+    fn compile_error(code: &str) -> CompileError {
+        match execute(code, &mut io::empty(), &mut io::sink()) {
+            Err(Error::Compile(err)) => err,
+            other => panic!("expected a compile error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unmatched_close() {
+        assert_eq!(
+            compile_error("+\n+]"),
+            CompileError::UnmatchedClose {
+                offset: 3,
+                line: 2,
+                column: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_unmatched_open() {
+        assert_eq!(
+            compile_error("[[]"),
+            CompileError::UnmatchedOpen {
+                offset: 0,
+                line: 1,
+                column: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut output = Vec::new();
+        let stats = execute(",+.", &mut Cursor::new(vec![1u8]), &mut output).unwrap();
+        assert_eq!(
+            stats,
+            ExecutionStats {
+                steps: 3,
+                bytes_read: 1,
+                bytes_written: 1
+            }
+        );
+    }
+
+    struct BrokenPipe;
+
+    impl Read for BrokenPipe {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn test_read_error() {
+        let err = match execute("+>,", BrokenPipe, &mut io::sink()) {
+            Err(Error::Runtime(err)) => err,
+            other => panic!("expected a runtime error, got {:?}", other),
+        };
+        assert!(matches!(err.kind, RuntimeErrorKind::Read(_)));
+        assert_eq!((err.index, err.pointer), (1, 0));
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let builder = VmBuilder::new().optimize(false).step_limit(Some(4));
+        assert!(run_with(builder.clone(), "++++").is_ok());
+        let err = run_with(builder.clone(), "+++++").unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::StepLimitExceeded));
+        assert_eq!(err.index, 4);
+        let err = run_with(builder.clone(), "+[]").unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::StepLimitExceeded));
+        assert_eq!(err.index, 2);
+        #[cfg(feature = "jit")]
+        assert!(run_with(builder.backend(Backend::Jit), "+[]").is_err());
+    }
+
+    #[test]
+    fn test_wrapped_multiply_loop() {
+        // The loop adds to the cell it counts down, so it never ends.
+        let builder = VmBuilder::new().tape_len(3).step_limit(Some(1000));
+        for optimize in [false, true] {
+            let mut vm = builder
+                .clone()
+                .optimize(optimize)
+                .build(io::empty(), io::sink());
+            vm.compile("++[->>>+<<<]").unwrap();
+            let err = vm.run().unwrap_err();
+            assert!(matches!(err.kind, RuntimeErrorKind::StepLimitExceeded));
+        }
+    }
+
+    #[test]
+    fn test_multiply_loop_bounds() {
+        // The pointer passes the end of the tape before coming back.
+        let code = "+[->>><+<<]";
+        for optimize in [false, true] {
+            let mut vm = VmBuilder::new()
+                .pointer_mode(PointerMode::Error)
+                .tape_len(3)
+                .optimize(optimize)
+                .build(io::empty(), io::sink());
+            vm.compile(code).unwrap();
+            let err = vm.run().unwrap_err();
+            assert!(matches!(err.kind, RuntimeErrorKind::PointerOutOfBounds));
+
+            let mut vm = VmBuilder::new()
+                .pointer_mode(PointerMode::Extend)
+                .tape_len(3)
+                .optimize(optimize)
+                .build(io::empty(), io::sink());
+            vm.compile(code).unwrap();
+            vm.run().unwrap();
+            assert_eq!(vm.memory(), [0, 0, 1, 0]);
+        }
+    }
+
+    #[test]
+    fn test_offsets_keep_output_order() {
+        // The pointer leaves the tape before the output, and comes back.
+        for optimize in [false, true] {
+            let mut output = Vec::new();
+            let mut vm = VmBuilder::new()
+                .pointer_mode(PointerMode::Error)
+                .tape_len(2)
+                .optimize(optimize)
+                .build(io::empty(), &mut output);
+            vm.compile("+>>><<<.>>>+").unwrap();
+            let err = vm.run().unwrap_err();
+            assert!(matches!(err.kind, RuntimeErrorKind::PointerOutOfBounds));
+            drop(vm);
+            assert_eq!(output, b"");
+        }
+    }
+
+    #[test]
+    fn test_cancel() {
+        let token = Arc::new(AtomicBool::new(false));
+        let builder = VmBuilder::new().cancel_token(token.clone());
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            token.store(true, Ordering::Relaxed);
+        });
+        let err = run_with(builder.clone(), "+[]").unwrap_err();
+        canceller.join().unwrap();
+        assert!(matches!(err.kind, RuntimeErrorKind::Cancelled));
+        // A cancelled token stops programs before they start.
+        let err = run_with(builder, "+").unwrap_err();
+        assert_eq!(err.to_string(), "cancelled (instruction 0, pointer 0)");
+    }
+
+    #[test]
+    fn test_endless_scans_are_limited() {
+        // Once every cell is set, `[>]` never finds a zero cell.
+        let code = "+>+[>]";
+        let builder = VmBuilder::new().tape_len(2);
+        let mut vm = builder.clone().build(io::empty(), io::sink());
+        vm.compile(code).unwrap();
+        assert!(vm.instructions().contains(&Instruction::ScanRight(1)));
+        let err = run_with(builder.clone().step_limit(Some(1000)), code).unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::StepLimitExceeded));
+        let err = run_with(VmBuilder::new().step_limit(Some(1_000_000)), "+[[>]+]").unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::StepLimitExceeded));
+
+        let token = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                token.store(true, Ordering::Relaxed);
+            })
+        };
+        let err = run_with(builder.cancel_token(token), code).unwrap_err();
+        canceller.join().unwrap();
+        assert!(matches!(err.kind, RuntimeErrorKind::Cancelled));
+    }
+
+    fn eof_output(eof_policy: EofPolicy) -> Vec<u8> {
+        let mut output = Vec::new();
+        execute_with_eof_policy("+++,.", &mut io::empty(), &mut output, eof_policy).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_eof_policy() {
+        assert_eq!(eof_output(EofPolicy::Unchanged), vec![3u8]);
+        assert_eq!(eof_output(EofPolicy::Zero), vec![0u8]);
+        assert_eq!(eof_output(EofPolicy::MinusOne), vec![255u8]);
+    }
+
+    #[test]
+    fn test_eof_error() {
+        let err = match execute_with_eof_policy(",", &mut io::empty(), io::sink(), EofPolicy::Error)
+        {
+            Err(Error::Runtime(err)) => err,
+            other => panic!("expected a runtime error, got {:?}", other),
+        };
+        assert!(matches!(err.kind, RuntimeErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_cat() {
+        let mut output = Vec::new();
+        execute_with_eof_policy(",[.,]", &b"cat"[..], &mut output, EofPolicy::Zero).unwrap();
+        assert_eq!(output, b"cat");
+    }
+
+    fn run_with(builder: VmBuilder, code: &str) -> Result<Vec<u8>, RuntimeError> {
+        let mut output = Vec::new();
+        let mut vm = builder.build(io::empty(), &mut output);
+        vm.compile(code).unwrap();
+        vm.run()?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_pointer_wrap() {
+        let builder = VmBuilder::new().tape_len(3);
+        assert_eq!(run_with(builder.clone(), "<+>.").unwrap(), vec![0u8]);
+        assert_eq!(run_with(builder, "<+>>>.").unwrap(), vec![1u8]);
+    }
+
+    #[test]
+    fn test_pointer_error() {
+        let builder = VmBuilder::new()
+            .tape_len(2)
+            .pointer_mode(PointerMode::Error);
+        assert_eq!(run_with(builder.clone(), ">+.").unwrap(), vec![1u8]);
+        let err = run_with(builder.clone(), "+>.>").unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::PointerOutOfBounds));
+        assert_eq!((err.index, err.pointer), (2, 0));
+        let err = run_with(builder, "<").unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::PointerOutOfBounds));
+    }
+
+    #[test]
+    fn test_pointer_extend() {
+        let mut vm = VmBuilder::new()
+            .tape_len(1)
+            .pointer_mode(PointerMode::Extend)
+            .build(io::empty(), io::sink());
+        vm.compile(">>>+").unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.memory(), &[0, 0, 0, 1]);
+        assert_eq!(vm.pointer(), 3);
+        vm.reset();
+        assert_eq!(vm.memory(), &[0]);
+    }
+
+    #[test]
+    fn test_cell_width() {
+        // Prints 1 if 256 fits in a cell.
+        let code = "++++++++[>++++++++<-]>[<++++>-]<[[-]+.>]";
+        assert_eq!(run_with(VmBuilder::new(), code).unwrap(), b"");
+        let mut output = Vec::new();
+        let mut vm = VmBuilder::new()
+            .cell::<u16>()
+            .build(io::empty(), &mut output);
+        vm.compile(code).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.memory()[0], 1);
+        assert_eq!(output, vec![1u8]);
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let mut vm = VmBuilder::new()
+            .cell::<u32>()
+            .arithmetic(Arithmetic::Checked)
+            .build(io::empty(), io::sink());
+        vm.compile("+>-").unwrap();
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::CellOverflow));
+        assert_eq!((err.index, err.pointer), (1, 0));
+    }
+
+    #[cfg(feature = "bignum")]
+    #[test]
+    fn test_bignum_cells() {
+        use num_bigint::BigInt;
+
+        let mut vm = VmBuilder::new()
+            .cell::<BigInt>()
+            .build(io::empty(), io::sink());
+        vm.compile("++++++++[>++++++++<-]>[<++++>-]>-").unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.memory()[0], BigInt::from(256));
+        assert_eq!(vm.memory()[2], BigInt::from(-1));
+    }
+
+    #[test]
+    fn test_optimize() {
+        let mut vm = VirtualMachine::new(io::empty(), io::sink());
+        vm.compile("+++[>++<-.]").unwrap();
+        assert_eq!(vm.instructions().len(), 6);
+        let mut vm = VmBuilder::new()
+            .optimize(false)
+            .build(io::empty(), io::sink());
+        vm.compile("+++[>++<-.]").unwrap();
+        assert_eq!(vm.instructions().len(), 11);
+    }
+
+    fn assert_optimized_matches(code: &str, input: &[u8]) {
+        let run = |optimize| {
+            let mut output = Vec::new();
+            let mut vm = VmBuilder::new()
+                .optimize(optimize)
+                .eof_policy(EofPolicy::Zero)
+                .build(input, &mut output);
+            vm.compile(code).unwrap();
+            vm.run().unwrap();
+            let (memory, pointer) = (vm.memory().to_vec(), vm.pointer());
+            (output, memory, pointer)
+        };
+        assert_eq!(run(true), run(false), "{}", code);
+    }
+
+    #[test]
+    fn test_idioms() {
+        assert_optimized_matches("+++++[-]>+[+]", b"");
+        assert_optimized_matches(",[->+>---<<]>[-<+>]<.", b"\x07");
+        assert_optimized_matches(",[+>+<]>.", b"\x07");
+        assert_optimized_matches("+>+>+>+>>>+<<<[<]+>[>]>[>>]<<<[<<<]", b"");
+        assert_optimized_matches("<[>]+<<<<[<<]", b"");
+        assert_optimized_matches(">,>,<[>>+.<<-.]>>[-<.+>]<<<<.", b"\x03\x05");
+    }
+
+    #[test]
+    fn test_scan_wraps() {
+        let builder = VmBuilder::new().tape_len(4);
+        let mut vm = builder.clone().build(io::empty(), io::sink());
+        vm.compile(">>+>+[>]").unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.pointer(), 0);
+        vm.compile("+>>+<<[<]").unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.pointer(), 3);
+        let err = run_with(builder.pointer_mode(PointerMode::Error), "+>+>+>+[>]").unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::PointerOutOfBounds));
+    }
+
+    #[test]
+    fn test_dead_code() {
+        assert_optimized_matches("[comment, with. commands<>]+++.", b"");
+        assert_optimized_matches(",[.,][-]>[<]<[+]", b"abc");
+        assert_optimized_matches("++[->+++<]>[.-]<[.]", b"");
+        assert_optimized_matches("+>+<[>[-]<-]>[.]>[-]<<[-]", b"");
+        assert_optimized_matches("-+[.]<<[-]>>[>]", b"");
+        assert_optimized_matches(",>,<[->-<]>[.[-]]", b"\x02\x02");
+        assert_optimized_matches(&format!("{}[.-]", "+".repeat(256)), b"");
+    }
+
+    #[test]
+    fn test_compile_keeping_tape() {
+        for optimize in [true, false] {
+            let mut output = Vec::new();
+            let mut vm = VmBuilder::new()
+                .optimize(optimize)
+                .build(io::empty(), &mut output);
+            vm.compile("+++>++").unwrap();
+            vm.run().unwrap();
+            vm.compile_keeping_tape("[.-]<[.-]").unwrap();
+            vm.run().unwrap();
+            assert_eq!(vm.pointer(), 0);
+            assert!(vm.compile_keeping_tape("]").is_err());
+            vm.compile("[.-]").unwrap();
+            vm.run().unwrap();
+            assert_eq!(output, [2, 1, 3, 2, 1]);
+        }
+    }
+
+    #[test]
+    fn test_corpus() {
+        for program in corpus::PROGRAMS {
+            let run = |optimize| {
+                let mut output = Vec::new();
+                let mut vm = VmBuilder::new()
+                    .optimize(optimize)
+                    .eof_policy(program.eof_policy)
+                    .build(program.input, &mut output);
+                vm.compile(program.code).unwrap();
+                vm.run().unwrap();
+                output
+            };
+            assert_eq!(run(true), run(false), "{}", program.name);
+        }
+    }
+
+    const HELLO: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+    /// Counts the calls to `write`.
+    struct CountingWriter(Vec<u8>, usize);
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1 += 1;
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_partial_eval_generator() {
+        let mut output = CountingWriter(Vec::new(), 0);
+        let mut vm = VmBuilder::new()
+            .partial_eval(Some(100_000))
+            .build(io::empty(), &mut output);
+        vm.compile(HELLO).unwrap();
+        let stats = vm.run().unwrap();
+        assert_eq!((stats.steps, stats.bytes_written), (0, 13));
+        assert_eq!(output.0, b"Hello World!\n");
+        assert_eq!(output.1, 1);
+    }
+
+    fn partial_eval_output(code: &str, input: &[u8], budget: u64) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut vm = VmBuilder::new()
+            .partial_eval(Some(budget))
+            .build(input, &mut output);
+        vm.compile(code).unwrap();
+        vm.run().unwrap();
+        output
+    }
+
+    #[test]
+    fn test_partial_eval_stops_at_input() {
+        let code = "++++++++[>++++++++<-]>+.,.<++[>.<-]";
+        assert_eq!(partial_eval_output(code, b"x", 1000), b"Axxx");
+        for budget in 0..20 {
+            assert_eq!(partial_eval_output(code, b"x", budget), b"Axxx");
+        }
+    }
+
+    #[test]
+    fn test_partial_eval_bounds_scans() {
+        // The scan never finds a zero cell, so the budget has to stop it.
+        let mut vm = VmBuilder::new()
+            .partial_eval(Some(1_000_000))
+            .step_limit(Some(10))
+            .build(io::empty(), io::sink());
+        vm.compile("+[[>]+]").unwrap();
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::StepLimitExceeded));
+        assert!(err.pointer > 0);
+    }
+
+    #[test]
+    fn test_partial_eval_leaves_errors_to_run() {
+        let mut vm = VmBuilder::new()
+            .pointer_mode(PointerMode::Error)
+            .optimize(false)
+            .partial_eval(Some(1000))
+            .build(io::empty(), io::sink());
+        vm.compile("+.<").unwrap();
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::PointerOutOfBounds));
+        assert_eq!(err.index, 2);
+    }
 }
@@ -0,0 +1,250 @@
+//! Running programs over asynchronous I/O.
+//!
+//! The interpreter itself stays synchronous: it runs on an [`InputQueue`] and
+//! a byte buffer, and stops whenever `,` needs more input than was read so
+//! far or a slice of steps has run. The runner then moves bytes between those
+//! buffers and the async streams, or yields to the executor.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{
+    Cell, CompileError, ExecutionStats, InputQueue, RuntimeError, RuntimeErrorKind, Status,
+    VirtualMachine, VmBuilder,
+};
+
+/// Default number of steps between yields.
+pub(super) const YIELD_INTERVAL: u64 = 10_000;
+
+/// Size of the reads from the input stream.
+const READ_SIZE: usize = 4096;
+
+impl<C: Cell> VmBuilder<C> {
+    /// Sets how many instructions an [`AsyncVirtualMachine`] runs before
+    /// yielding to the executor, 10 000 by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `steps` is zero.
+    pub fn yield_interval(mut self, steps: u64) -> VmBuilder<C> {
+        assert!(steps > 0, "yield interval must be positive");
+        self.config.yield_interval = steps;
+        self
+    }
+
+    pub fn build_async<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+        self,
+        input: R,
+        output: W,
+    ) -> AsyncVirtualMachine<R, W, C> {
+        AsyncVirtualMachine {
+            vm: self.build(InputQueue::new(), Vec::new()),
+            input,
+            output,
+        }
+    }
+}
+
+/// A Brainfuck interpreter reading `,` from the async `input` and writing `.`
+/// to the async `output`. Programs are always interpreted, and output is
+/// written whenever the program waits for input, yields or ends.
+pub struct AsyncVirtualMachine<R, W, C: Cell = u8> {
+    vm: VirtualMachine<InputQueue, Vec<u8>, C>,
+    input: R,
+    output: W,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncVirtualMachine<R, W> {
+    /// Creates a machine with 8-bit cells and the default configuration.
+    pub fn new(input: R, output: W) -> AsyncVirtualMachine<R, W> {
+        VmBuilder::new().build_async(input, output)
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin, C: Cell> AsyncVirtualMachine<R, W, C> {
+    pub fn memory(&self) -> &[C] {
+        self.vm.memory()
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.vm.pointer()
+    }
+
+    /// Compiles `code`, replacing any previous program and resetting the tape.
+    pub fn compile(&mut self, code: &str) -> Result<(), CompileError> {
+        self.vm.compile(code)
+    }
+
+    /// Runs the compiled program from its first instruction.
+    pub async fn run(&mut self) -> Result<ExecutionStats, RuntimeError> {
+        self.vm.start()?;
+        let mut buf = [0; READ_SIZE];
+        loop {
+            let status = self.vm.run_for(Some(self.vm.config.yield_interval));
+            // Output from before an error still reaches the writer.
+            self.write_output().await?;
+            if status.is_err() {
+                let _ = self.output.flush().await;
+            }
+            match status? {
+                Some(Status::Halted) => {
+                    self.output
+                        .flush()
+                        .await
+                        .map_err(|err| self.write_error(err))?;
+                    return Ok(self.vm.stats());
+                }
+                Some(Status::NeedsInput) => {
+                    // Anything written so far may be a prompt for the input.
+                    self.output
+                        .flush()
+                        .await
+                        .map_err(|err| self.write_error(err))?;
+                    match self.input.read(&mut buf).await {
+                        Ok(0) => self.vm.close_input(),
+                        Ok(len) => self.vm.feed(&buf[..len]),
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                        Err(err) => {
+                            return Err(self.vm.error(RuntimeErrorKind::Read(err), self.vm.index))
+                        }
+                    }
+                }
+                None => YieldNow(false).await,
+            }
+        }
+    }
+
+    /// Writes out the output buffered since the last call.
+    async fn write_output(&mut self) -> Result<(), RuntimeError> {
+        if !self.vm.output.is_empty() {
+            let result = self.output.write_all(&self.vm.output).await;
+            self.vm.output.clear();
+            result.map_err(|err| self.write_error(err))?;
+        }
+        Ok(())
+    }
+
+    fn write_error(&self, err: io::Error) -> RuntimeError {
+        self.vm.error(RuntimeErrorKind::Write(err), self.vm.index)
+    }
+}
+
+/// Returns control to the executor once, waking the task straight away.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_duplex() {
+        let (client, server) = io::duplex(16);
+        let (input, output) = io::split(server);
+        let mut vm = VmBuilder::new()
+            .eof_policy(EofPolicy::Zero)
+            .build_async(input, output);
+        vm.compile(",[.,]").unwrap();
+        // Far more than the streams buffer, so the program has to wait for
+        // the client to read its output before it can read any more input.
+        let text = b"abcdefghijklmnopqrstuvwxyz".repeat(10);
+        let (mut reader, mut writer) = io::split(client);
+        let write = async {
+            writer.write_all(&text).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let mut echoed = Vec::new();
+        let read = reader.read_to_end(&mut echoed);
+        let run = async {
+            let stats = vm.run().await.unwrap();
+            // Closing the output lets the client see the end of the stream.
+            drop(vm);
+            stats
+        };
+        let (stats, (), read) = tokio::join!(run, write, read);
+        read.unwrap();
+        assert_eq!(echoed, text);
+        assert_eq!((stats.bytes_read, stats.bytes_written), (260, 260));
+    }
+
+    #[tokio::test]
+    async fn test_yields() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        tokio::spawn(async move { flag.store(true, Ordering::Relaxed) });
+        let mut vm = VmBuilder::new()
+            .optimize(false)
+            .yield_interval(100)
+            .build_async(io::empty(), io::sink());
+        vm.compile("++++++++[>++++++++<-]>[>++++++++[-]<-]")
+            .unwrap();
+        let stats = vm.run().await.unwrap();
+        assert!(stats.steps > 1000);
+        // The spawned task only runs if the program gave up the thread.
+        assert!(ran.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_yields_during_scans() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        tokio::spawn(async move { flag.store(true, Ordering::Relaxed) });
+        let mut vm = VmBuilder::new()
+            .yield_interval(100)
+            .step_limit(Some(1_000_000))
+            .build_async(io::empty(), io::sink());
+        // Nearly every step is spent in the scan, which never ends.
+        vm.compile("+[[>]+]").unwrap();
+        let err = vm.run().await.unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::StepLimitExceeded));
+        assert!(ran.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let mut vm = VmBuilder::new()
+            .eof_policy(EofPolicy::Error)
+            .build_async(io::empty(), io::sink());
+        vm.compile("+.,").unwrap();
+        let err = vm.run().await.unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::UnexpectedEof));
+        assert_eq!(err.index, 2);
+
+        let mut output = Vec::new();
+        let mut vm = VmBuilder::new()
+            .pointer_mode(PointerMode::Error)
+            .build_async(io::empty(), &mut output);
+        vm.compile("+++.<").unwrap();
+        let err = vm.run().await.unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::PointerOutOfBounds));
+        drop(vm);
+        assert_eq!(output, [3]);
+
+        let (client, server) = io::duplex(1);
+        drop(client);
+        let mut vm = AsyncVirtualMachine::new(io::empty(), server);
+        vm.compile("+.").unwrap();
+        let err = vm.run().await.unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::Write(_)));
+        assert_eq!(vm.memory()[0], 1);
+    }
+}
@@ -0,0 +1,242 @@
+use std::fmt;
+
+use super::snapshot::{read_varint, write_varint};
+
+/// How cell arithmetic behaves when a value leaves the range of its cell type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Arithmetic {
+    /// Wrap around modulo the cell width.
+    #[default]
+    Wrapping,
+    /// Stop with [`RuntimeErrorKind::CellOverflow`](super::RuntimeErrorKind::CellOverflow).
+    Checked,
+}
+
+/// A value that can be stored on the tape.
+pub trait Cell: Clone + Default + PartialEq + fmt::Debug + 'static {
+    /// Width of the cell in bits, or `None` for unbounded cells.
+    const BITS: Option<u32>;
+
+    fn is_zero(&self) -> bool;
+
+    /// The value stored by `,` when it reads `byte`.
+    fn from_byte(byte: u8) -> Self;
+
+    /// The byte written by `.`, i.e. the low 8 bits of the value.
+    fn to_byte(&self) -> u8;
+
+    /// The value stored by `,` at end of input under `EofPolicy::MinusOne`.
+    fn minus_one() -> Self;
+
+    /// Adds `delta` to the cell, returning `None` if checked arithmetic
+    /// overflows.
+    fn add(&self, delta: i64, arithmetic: Arithmetic) -> Option<Self>;
+
+    /// Adds `source * factor` to the cell, returning `None` if checked
+    /// arithmetic overflows.
+    fn mul_add(&self, source: &Self, factor: i64, arithmetic: Arithmetic) -> Option<Self>;
+
+    /// Appends the value to `out` in the format of [`VmState::to_bytes`](super::VmState::to_bytes).
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Reads a value written by [`encode`](Cell::encode) from the start of
+    /// `bytes`, returning it with the number of bytes it took.
+    fn decode(bytes: &[u8]) -> Option<(Self, usize)>;
+
+    /// Index of the first zero cell in `cells`.
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().position(Cell::is_zero)
+    }
+
+    /// Index of the last zero cell in `cells`.
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().rposition(Cell::is_zero)
+    }
+}
+
+macro_rules! impl_cell {
+    ($($ty:ty $({ $($extra:item)* })?),*) => {
+        $(
+            impl Cell for $ty {
+                const BITS: Option<u32> = Some(<$ty>::BITS);
+
+                fn is_zero(&self) -> bool {
+                    *self == 0
+                }
+
+                fn from_byte(byte: u8) -> $ty {
+                    byte.into()
+                }
+
+                fn to_byte(&self) -> u8 {
+                    *self as u8
+                }
+
+                fn minus_one() -> $ty {
+                    <$ty>::MAX
+                }
+
+                fn add(&self, delta: i64, arithmetic: Arithmetic) -> Option<$ty> {
+                    match arithmetic {
+                        Arithmetic::Wrapping => Some(self.wrapping_add(delta as $ty)),
+                        Arithmetic::Checked if delta >= 0 => {
+                            self.checked_add(<$ty>::try_from(delta).ok()?)
+                        }
+                        Arithmetic::Checked => {
+                            self.checked_sub(<$ty>::try_from(delta.unsigned_abs()).ok()?)
+                        }
+                    }
+                }
+
+                fn mul_add(&self, source: &$ty, factor: i64, arithmetic: Arithmetic) -> Option<$ty> {
+                    match arithmetic {
+                        Arithmetic::Wrapping => {
+                            Some(self.wrapping_add(source.wrapping_mul(factor as $ty)))
+                        }
+                        Arithmetic::Checked => {
+                            let product = i128::from(*source).checked_mul(factor.into())?;
+                            <$ty>::try_from(i128::from(*self).checked_add(product)?).ok()
+                        }
+                    }
+                }
+
+                fn encode(&self, out: &mut Vec<u8>) {
+                    write_varint(out, *self as u64);
+                }
+
+                fn decode(bytes: &[u8]) -> Option<($ty, usize)> {
+                    let (value, len) = read_varint(bytes)?;
+                    Some((<$ty>::try_from(value).ok()?, len))
+                }
+
+                $($($extra)*)?
+            }
+        )*
+    };
+}
+
+impl_cell!(
+    u8 {
+        fn find_zero(cells: &[u8]) -> Option<usize> {
+            memchr::memchr(0, cells)
+        }
+
+        fn rfind_zero(cells: &[u8]) -> Option<usize> {
+            memchr::memrchr(0, cells)
+        }
+    },
+    u16,
+    u32,
+    u64
+);
+
+/// Unbounded cells; arithmetic never overflows, so both modes behave alike.
+#[cfg(feature = "bignum")]
+impl Cell for num_bigint::BigInt {
+    const BITS: Option<u32> = None;
+
+    fn is_zero(&self) -> bool {
+        self.sign() == num_bigint::Sign::NoSign
+    }
+
+    fn from_byte(byte: u8) -> num_bigint::BigInt {
+        byte.into()
+    }
+
+    fn to_byte(&self) -> u8 {
+        self.to_signed_bytes_le()[0]
+    }
+
+    fn minus_one() -> num_bigint::BigInt {
+        (-1).into()
+    }
+
+    fn add(&self, delta: i64, _: Arithmetic) -> Option<num_bigint::BigInt> {
+        Some(self + delta)
+    }
+
+    fn mul_add(
+        &self,
+        source: &num_bigint::BigInt,
+        factor: i64,
+        _: Arithmetic,
+    ) -> Option<num_bigint::BigInt> {
+        Some(self + source * factor)
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let bytes = self.to_signed_bytes_le();
+        write_varint(out, bytes.len() as u64);
+        out.extend(bytes);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(num_bigint::BigInt, usize)> {
+        let (len, start) = read_varint(bytes)?;
+        let end = start.checked_add(usize::try_from(len).ok()?)?;
+        let value = num_bigint::BigInt::from_signed_bytes_le(bytes.get(start..end)?);
+        Some((value, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrapping() {
+        assert_eq!(255u8.add(1, Arithmetic::Wrapping), Some(0));
+        assert_eq!(0u16.add(-1, Arithmetic::Wrapping), Some(u16::MAX));
+        assert_eq!(1u32.add(-300, Arithmetic::Wrapping), Some(u32::MAX - 298));
+        assert_eq!(u64::MAX.to_byte(), 255);
+    }
+
+    #[test]
+    fn test_mul_add() {
+        assert_eq!(10u8.mul_add(&3, 2, Arithmetic::Checked), Some(16));
+        assert_eq!(10u8.mul_add(&3, -4, Arithmetic::Checked), None);
+        assert_eq!(10u8.mul_add(&3, -4, Arithmetic::Wrapping), Some(254));
+        assert_eq!(0u16.mul_add(&300, 300, Arithmetic::Checked), None);
+    }
+
+    #[test]
+    fn test_find_zero() {
+        assert_eq!(u8::find_zero(&[1, 0, 2, 0]), Some(1));
+        assert_eq!(u8::rfind_zero(&[1, 0, 2, 0]), Some(3));
+        assert_eq!(u32::find_zero(&[1, 2]), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut out = Vec::new();
+        300u16.encode(&mut out);
+        0u8.encode(&mut out);
+        assert_eq!(out, [0xAC, 0x02, 0]);
+        assert_eq!(u16::decode(&out), Some((300, 2)));
+        assert_eq!(u8::decode(&out), None);
+        assert_eq!(u8::decode(&out[2..]), Some((0, 1)));
+        assert_eq!(u64::decode(&out[..1]), None);
+    }
+
+    #[test]
+    fn test_checked() {
+        assert_eq!(254u8.add(1, Arithmetic::Checked), Some(255));
+        assert_eq!(255u8.add(1, Arithmetic::Checked), None);
+        assert_eq!(0u32.add(-1, Arithmetic::Checked), None);
+        assert_eq!(10u8.add(300, Arithmetic::Checked), None);
+    }
+
+    #[cfg(feature = "bignum")]
+    #[test]
+    fn test_bignum() {
+        use num_bigint::BigInt;
+
+        let minus_one = BigInt::default().add(-1, Arithmetic::Checked).unwrap();
+        assert_eq!(minus_one, BigInt::minus_one());
+        assert_eq!(minus_one.to_byte(), 255);
+        assert_eq!(BigInt::from(256 + 65).to_byte(), b'A');
+        assert!(minus_one.add(1, Arithmetic::Checked).unwrap().is_zero());
+        let mut out = Vec::new();
+        minus_one.encode(&mut out);
+        assert_eq!(BigInt::decode(&out), Some((minus_one, 2)));
+    }
+}
@@ -0,0 +1,32 @@
+//! Translation of compiled programs to source code for other toolchains.
+//!
+//! Generators take a [`VirtualMachine`](super::VirtualMachine) that has
+//! compiled a program and translate its instructions under the machine's
+//! configuration. Programs always start from their first instruction, so any
+//! work done by [`VmBuilder::partial_eval`](super::VmBuilder::partial_eval) is
+//! repeated by the generated code.
+
+use std::error;
+use std::fmt;
+
+pub mod asm;
+pub mod c;
+pub mod elf;
+pub mod llvm;
+pub mod rust;
+pub mod wasm;
+
+/// A configuration that a code generator cannot translate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported {
+    /// What the generator could not handle, e.g. "unbounded cells".
+    pub what: &'static str,
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} not supported by this code generator", self.what)
+    }
+}
+
+impl error::Error for Unsupported {}
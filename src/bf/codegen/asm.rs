@@ -0,0 +1,744 @@
+//! x86-64 assembly generation for Linux.
+//!
+//! The generated program needs no C runtime: it starts at `_start`, keeps the
+//! tape in `.bss` and calls `read`, `write` and `exit` directly, a byte at a
+//! time. It exits with status 1 and a message on standard error where the
+//! interpreter would return a [`RuntimeError`](crate::bf::RuntimeError).
+//! Assemble and link it with, for example, `nasm -f elf64 prog.asm && ld
+//! prog.o` or `as prog.s -o prog.o && ld prog.o`.
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+
+use super::super::x86::{Cond, Mem, Reg};
+use super::super::{Arithmetic, Cell, EofPolicy, Instruction, PointerMode, VirtualMachine};
+use super::Unsupported;
+
+/// The assembler dialect to generate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Intel syntax for NASM.
+    Nasm,
+    /// AT&T syntax for the GNU assembler.
+    Gas,
+}
+
+/// Translates the program compiled by `vm` into assembly using the same tape
+/// length, pointer mode and EOF policy. Only 8-bit cells with wrapping
+/// arithmetic on a fixed-size tape are supported.
+pub fn generate<R: Read, W: Write, C: Cell>(
+    vm: &VirtualMachine<R, W, C>,
+    syntax: Syntax,
+) -> Result<String, Unsupported> {
+    let mut text = Text {
+        syntax,
+        out: String::new(),
+        labels: 0,
+    };
+    text.out.push_str(match syntax {
+        Syntax::Nasm => "bits 64\nglobal _start\n\nsection .text\n_start:\n",
+        Syntax::Gas => "    .globl _start\n\n    .text\n_start:\n",
+    });
+    lower(vm, &mut text)?;
+
+    let mut out = text.out;
+    out.push_str(match syntax {
+        Syntax::Nasm => "\nsection .rodata\n",
+        Syntax::Gas => "\n    .section .rodata\n",
+    });
+    for message in Message::ALL {
+        let _ = match syntax {
+            Syntax::Nasm => writeln!(
+                out,
+                "message{}: db \"{}\", 10",
+                message as usize,
+                message.text()
+            ),
+            Syntax::Gas => writeln!(
+                out,
+                "message{}: .ascii \"{}\\n\"",
+                message as usize,
+                message.text()
+            ),
+        };
+    }
+    let _ = match syntax {
+        Syntax::Nasm => write!(
+            out,
+            "\nsection .bss\ntape: resb {}\n\n\
+             section .note.GNU-stack noalloc noexec nowrite progbits\n",
+            vm.config.tape_len
+        ),
+        Syntax::Gas => write!(
+            out,
+            "\n    .bss\ntape: .skip {}\n\n    .section .note.GNU-stack,\"\",@progbits\n",
+            vm.config.tape_len
+        ),
+    };
+    Ok(out)
+}
+
+/// Why a generated program stops early.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Message {
+    PointerOutOfBounds,
+    UnexpectedEof,
+    Read,
+    Write,
+}
+
+impl Message {
+    pub(super) const ALL: [Message; 4] = [
+        Message::PointerOutOfBounds,
+        Message::UnexpectedEof,
+        Message::Read,
+        Message::Write,
+    ];
+
+    /// The start of the interpreter's message for the same error.
+    pub(super) fn text(self) -> &'static str {
+        match self {
+            Message::PointerOutOfBounds => "pointer out of bounds",
+            Message::UnexpectedEof => "unexpected end of input",
+            Message::Read => "failed to read input",
+            Message::Write => "failed to write output",
+        }
+    }
+}
+
+/// Where [`lower`] sends machine instructions: assembly text or the
+/// [`Assembler`](super::super::x86::Assembler).
+pub(super) trait Target {
+    type Label: Copy;
+
+    fn label(&mut self) -> Self::Label;
+    fn bind(&mut self, label: Self::Label);
+    fn jmp(&mut self, target: Self::Label);
+    fn jcc(&mut self, cond: Cond, target: Self::Label);
+    fn mov_imm(&mut self, dst: Reg, imm: u64);
+    fn mov(&mut self, dst: Reg, src: Reg);
+    fn lea(&mut self, dst: Reg, mem: Mem);
+    fn cmp(&mut self, a: Reg, b: Reg);
+    fn test32(&mut self, a: Reg, b: Reg);
+    fn cqo(&mut self);
+    fn idiv(&mut self, src: Reg);
+    fn imul32(&mut self, dst: Reg, src: Reg, imm: i32);
+    fn load_byte(&mut self, dst: Reg, mem: Mem);
+    fn add_byte(&mut self, mem: Mem, imm: u8);
+    fn add_byte_reg(&mut self, mem: Mem, src: Reg);
+    fn store_byte(&mut self, mem: Mem, imm: u8);
+    fn cmp_byte(&mut self, mem: Mem, imm: u8);
+    fn syscall(&mut self);
+    /// Loads the address of the tape.
+    fn tape(&mut self, dst: Reg);
+    /// Loads the address of a message, which ends in a newline.
+    fn message(&mut self, dst: Reg, message: Message);
+}
+
+// Linux system call numbers.
+const READ: u64 = 0;
+const WRITE: u64 = 1;
+const EXIT: u64 = 60;
+
+/// The current cell: rbx holds the tape and r12 the pointer.
+const CURRENT: Mem = Mem {
+    base: Reg::Rbx,
+    index: Some(Reg::R12),
+    disp: 0,
+};
+
+/// Emits the whole program, from `_start` to the error exits.
+pub(super) fn lower<R: Read, W: Write, C: Cell, T: Target>(
+    vm: &VirtualMachine<R, W, C>,
+    t: &mut T,
+) -> Result<(), Unsupported> {
+    match C::BITS {
+        Some(8) => {}
+        None => {
+            return Err(Unsupported {
+                what: "unbounded cells",
+            })
+        }
+        Some(_) => {
+            return Err(Unsupported {
+                what: "cells wider than 8 bits",
+            })
+        }
+    }
+    let config = &vm.config;
+    if config.arithmetic == Arithmetic::Checked {
+        return Err(Unsupported {
+            what: "checked arithmetic",
+        });
+    }
+    if config.pointer_mode == PointerMode::Extend {
+        return Err(Unsupported {
+            what: "growing tapes",
+        });
+    }
+
+    let mut l = Lowering {
+        fails: Message::ALL.map(|_| t.label()),
+        t,
+        pointer_mode: config.pointer_mode,
+        loops: Vec::new(),
+    };
+    l.t.tape(Reg::Rbx);
+    l.t.mov_imm(Reg::R12, 0);
+    l.t.mov_imm(Reg::R14, config.tape_len as u64);
+    for &instruction in &vm.instructions {
+        match instruction {
+            Instruction::Add { offset, delta } => {
+                let cell = l.cell(offset)?;
+                l.t.add_byte(cell, delta as u8);
+            }
+            Instruction::Move(delta) => l.move_pointer(delta)?,
+            Instruction::Input { offset } => {
+                let cell = l.cell(offset)?;
+                l.t.lea(Reg::Rsi, cell);
+                l.syscall(READ, 0);
+                l.t.test32(Reg::Rax, Reg::Rax);
+                l.fail_if(Cond::S, Message::Read);
+                let value = match config.eof_policy {
+                    EofPolicy::Unchanged => continue,
+                    EofPolicy::Zero => 0,
+                    EofPolicy::MinusOne => 0xFF,
+                    EofPolicy::Error => {
+                        l.fail_if(Cond::E, Message::UnexpectedEof);
+                        continue;
+                    }
+                };
+                let done = l.t.label();
+                l.t.jcc(Cond::Ne, done);
+                l.t.store_byte(Mem::new(Reg::Rsi, 0), value);
+                l.t.bind(done);
+            }
+            Instruction::Output { offset } => {
+                let cell = l.cell(offset)?;
+                l.t.lea(Reg::Rsi, cell);
+                l.syscall(WRITE, 1);
+                l.t.test32(Reg::Rax, Reg::Rax);
+                l.fail_if(Cond::Le, Message::Write);
+            }
+            Instruction::LoopStart(_) => {
+                let (body, after) = (l.t.label(), l.t.label());
+                l.t.cmp_byte(CURRENT, 0);
+                l.t.jcc(Cond::E, after);
+                l.t.bind(body);
+                l.loops.push((body, after));
+            }
+            Instruction::LoopEnd(_) => {
+                let (body, after) = l.loops.pop().expect("balanced loops");
+                l.t.cmp_byte(CURRENT, 0);
+                l.t.jcc(Cond::Ne, body);
+                l.t.bind(after);
+            }
+            Instruction::SetZero { offset } => {
+                let cell = l.cell(offset)?;
+                l.t.store_byte(cell, 0);
+            }
+            Instruction::MulAdd { offset, factor } => {
+                let skip = l.t.label();
+                l.t.cmp_byte(CURRENT, 0);
+                l.t.jcc(Cond::E, skip);
+                l.t.load_byte(Reg::Rcx, CURRENT);
+                l.t.imul32(Reg::Rcx, Reg::Rcx, factor);
+                let cell = l.cell(offset)?;
+                l.t.add_byte_reg(cell, Reg::Rcx);
+                l.t.bind(skip);
+            }
+            Instruction::ScanRight(stride) | Instruction::ScanLeft(stride) => {
+                let stride = stride as isize;
+                let (top, done) = (l.t.label(), l.t.label());
+                l.t.bind(top);
+                l.t.cmp_byte(CURRENT, 0);
+                l.t.jcc(Cond::E, done);
+                l.move_pointer(match instruction {
+                    Instruction::ScanLeft(_) => -stride,
+                    _ => stride,
+                })?;
+                l.t.jmp(top);
+                l.t.bind(done);
+            }
+        }
+    }
+    l.exit(0);
+
+    // Each error exit writes its message to standard error and exits with 1.
+    let fail = l.t.label();
+    for (message, label) in Message::ALL.into_iter().zip(l.fails) {
+        l.t.bind(label);
+        l.t.message(Reg::Rsi, message);
+        l.t.mov_imm(Reg::Rdx, message.text().len() as u64 + 1);
+        l.t.jmp(fail);
+    }
+    l.t.bind(fail);
+    l.t.mov_imm(Reg::Rax, WRITE);
+    l.t.mov_imm(Reg::Rdi, 2);
+    l.t.syscall();
+    l.exit(1);
+    Ok(())
+}
+
+struct Lowering<'a, T: Target> {
+    t: &'a mut T,
+    pointer_mode: PointerMode,
+    /// Labels of the error exits, in the order of [`Message::ALL`].
+    fails: [T::Label; 4],
+    /// Labels of the open loops' bodies and exits.
+    loops: Vec<(T::Label, T::Label)>,
+}
+
+impl<T: Target> Lowering<'_, T> {
+    fn fail_if(&mut self, cond: Cond, message: Message) {
+        self.t.jcc(cond, self.fails[message as usize]);
+    }
+
+    /// Calls `read` or `write` on one byte at rsi.
+    fn syscall(&mut self, number: u64, fd: u64) {
+        self.t.mov_imm(Reg::Rax, number);
+        self.t.mov_imm(Reg::Rdi, fd);
+        self.t.mov_imm(Reg::Rdx, 1);
+        self.t.syscall();
+    }
+
+    fn exit(&mut self, status: u64) {
+        self.t.mov_imm(Reg::Rax, EXIT);
+        self.t.mov_imm(Reg::Rdi, status);
+        self.t.syscall();
+    }
+
+    /// The cell at `offset`. Unless `offset` is 0, its index is left in rax
+    /// after wrapping or checking it against the tape length in r14.
+    fn cell(&mut self, offset: isize) -> Result<Mem, Unsupported> {
+        if offset == 0 {
+            return Ok(CURRENT);
+        }
+        let offset = i32::try_from(offset).map_err(|_| Unsupported {
+            what: "offsets over 2 GiB",
+        })?;
+        self.t.lea(Reg::Rax, Mem::new(Reg::R12, offset));
+        self.t.cmp(Reg::Rax, Reg::R14);
+        match self.pointer_mode {
+            PointerMode::Wrap => {
+                // rax = ((rax % len) + len) % len
+                let inside = self.t.label();
+                self.t.jcc(Cond::B, inside);
+                self.t.cqo();
+                self.t.idiv(Reg::R14);
+                self.t.lea(Reg::Rax, Mem::indexed(Reg::Rdx, Reg::R14, 0));
+                self.t.cqo();
+                self.t.idiv(Reg::R14);
+                self.t.mov(Reg::Rax, Reg::Rdx);
+                self.t.bind(inside);
+            }
+            _ => self.fail_if(Cond::Ae, Message::PointerOutOfBounds),
+        }
+        Ok(Mem::indexed(Reg::Rbx, Reg::Rax, 0))
+    }
+
+    fn move_pointer(&mut self, delta: isize) -> Result<(), Unsupported> {
+        self.cell(delta)?;
+        self.t.mov(Reg::R12, Reg::Rax);
+        Ok(())
+    }
+}
+
+/// Writes assembly text.
+struct Text {
+    syntax: Syntax,
+    out: String,
+    labels: usize,
+}
+
+/// Register names by number, at 64, 32 and 8 bits.
+const NAMES: [[&str; 3]; 16] = [
+    ["rax", "eax", "al"],
+    ["rcx", "ecx", "cl"],
+    ["rdx", "edx", "dl"],
+    ["rbx", "ebx", "bl"],
+    ["rsp", "esp", "spl"],
+    ["rbp", "ebp", "bpl"],
+    ["rsi", "esi", "sil"],
+    ["rdi", "edi", "dil"],
+    ["r8", "r8d", "r8b"],
+    ["r9", "r9d", "r9b"],
+    ["r10", "r10d", "r10b"],
+    ["r11", "r11d", "r11b"],
+    ["r12", "r12d", "r12b"],
+    ["r13", "r13d", "r13b"],
+    ["r14", "r14d", "r14b"],
+    ["r15", "r15d", "r15b"],
+];
+
+impl Text {
+    fn line(&mut self, nasm: std::fmt::Arguments, gas: std::fmt::Arguments) {
+        let _ = match self.syntax {
+            Syntax::Nasm => writeln!(self.out, "    {}", nasm),
+            Syntax::Gas => writeln!(self.out, "    {}", gas),
+        };
+    }
+
+    /// The name of a register at `width`: 0 for 64 bits, 1 for 32 and 2 for
+    /// 8.
+    fn reg(&self, reg: Reg, width: usize) -> String {
+        let name = NAMES[reg as usize][width];
+        match self.syntax {
+            Syntax::Nasm => name.into(),
+            Syntax::Gas => format!("%{}", name),
+        }
+    }
+
+    fn mem(&self, mem: Mem) -> String {
+        match self.syntax {
+            Syntax::Nasm => {
+                let mut out = format!("[{}", self.reg(mem.base, 0));
+                if let Some(index) = mem.index {
+                    let _ = write!(out, " + {}", self.reg(index, 0));
+                }
+                match mem.disp {
+                    0 => {}
+                    disp if disp < 0 => {
+                        let _ = write!(out, " - {}", disp.unsigned_abs());
+                    }
+                    disp => {
+                        let _ = write!(out, " + {}", disp);
+                    }
+                }
+                out + "]"
+            }
+            Syntax::Gas => {
+                let mut out = match mem.disp {
+                    0 => String::new(),
+                    disp => disp.to_string(),
+                };
+                let _ = write!(out, "({}", self.reg(mem.base, 0));
+                if let Some(index) = mem.index {
+                    let _ = write!(out, ",{}", self.reg(index, 0));
+                }
+                out + ")"
+            }
+        }
+    }
+
+    fn cond(cond: Cond) -> &'static str {
+        match cond {
+            Cond::B => "b",
+            Cond::Ae => "ae",
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::S => "s",
+            Cond::Le => "le",
+        }
+    }
+}
+
+impl Target for Text {
+    type Label = usize;
+
+    fn label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    fn bind(&mut self, label: usize) {
+        let _ = writeln!(self.out, ".L{}:", label);
+    }
+
+    fn jmp(&mut self, target: usize) {
+        self.line(
+            format_args!("jmp .L{}", target),
+            format_args!("jmp .L{}", target),
+        );
+    }
+
+    fn jcc(&mut self, cond: Cond, target: usize) {
+        let cond = Text::cond(cond);
+        self.line(
+            format_args!("j{} .L{}", cond, target),
+            format_args!("j{} .L{}", cond, target),
+        );
+    }
+
+    fn mov_imm(&mut self, dst: Reg, imm: u64) {
+        let dst = self.reg(dst, 0);
+        self.line(
+            format_args!("mov {}, {}", dst, imm),
+            format_args!("movabs ${}, {}", imm, dst),
+        );
+    }
+
+    fn mov(&mut self, dst: Reg, src: Reg) {
+        let (dst, src) = (self.reg(dst, 0), self.reg(src, 0));
+        self.line(
+            format_args!("mov {}, {}", dst, src),
+            format_args!("mov {}, {}", src, dst),
+        );
+    }
+
+    fn lea(&mut self, dst: Reg, mem: Mem) {
+        let (dst, mem) = (self.reg(dst, 0), self.mem(mem));
+        self.line(
+            format_args!("lea {}, {}", dst, mem),
+            format_args!("lea {}, {}", mem, dst),
+        );
+    }
+
+    fn cmp(&mut self, a: Reg, b: Reg) {
+        let (a, b) = (self.reg(a, 0), self.reg(b, 0));
+        self.line(
+            format_args!("cmp {}, {}", a, b),
+            format_args!("cmp {}, {}", b, a),
+        );
+    }
+
+    fn test32(&mut self, a: Reg, b: Reg) {
+        let (a, b) = (self.reg(a, 1), self.reg(b, 1));
+        self.line(
+            format_args!("test {}, {}", a, b),
+            format_args!("test {}, {}", b, a),
+        );
+    }
+
+    fn cqo(&mut self) {
+        self.line(format_args!("cqo"), format_args!("cqto"));
+    }
+
+    fn idiv(&mut self, src: Reg) {
+        let src = self.reg(src, 0);
+        self.line(format_args!("idiv {}", src), format_args!("idivq {}", src));
+    }
+
+    fn imul32(&mut self, dst: Reg, src: Reg, imm: i32) {
+        let (dst, src) = (self.reg(dst, 1), self.reg(src, 1));
+        self.line(
+            format_args!("imul {}, {}, {}", dst, src, imm),
+            format_args!("imul ${}, {}, {}", imm, src, dst),
+        );
+    }
+
+    fn load_byte(&mut self, dst: Reg, mem: Mem) {
+        let (dst, mem) = (self.reg(dst, 1), self.mem(mem));
+        self.line(
+            format_args!("movzx {}, byte {}", dst, mem),
+            format_args!("movzbl {}, {}", mem, dst),
+        );
+    }
+
+    fn add_byte(&mut self, mem: Mem, imm: u8) {
+        let mem = self.mem(mem);
+        self.line(
+            format_args!("add byte {}, {}", mem, imm),
+            format_args!("addb ${}, {}", imm, mem),
+        );
+    }
+
+    fn add_byte_reg(&mut self, mem: Mem, src: Reg) {
+        let (mem, src) = (self.mem(mem), self.reg(src, 2));
+        self.line(
+            format_args!("add {}, {}", mem, src),
+            format_args!("add {}, {}", src, mem),
+        );
+    }
+
+    fn store_byte(&mut self, mem: Mem, imm: u8) {
+        let mem = self.mem(mem);
+        self.line(
+            format_args!("mov byte {}, {}", mem, imm),
+            format_args!("movb ${}, {}", imm, mem),
+        );
+    }
+
+    fn cmp_byte(&mut self, mem: Mem, imm: u8) {
+        let mem = self.mem(mem);
+        self.line(
+            format_args!("cmp byte {}, {}", mem, imm),
+            format_args!("cmpb ${}, {}", imm, mem),
+        );
+    }
+
+    fn syscall(&mut self) {
+        self.line(format_args!("syscall"), format_args!("syscall"));
+    }
+
+    fn tape(&mut self, dst: Reg) {
+        let dst = self.reg(dst, 0);
+        self.line(
+            format_args!("lea {}, [rel tape]", dst),
+            format_args!("lea tape(%rip), {}", dst),
+        );
+    }
+
+    fn message(&mut self, dst: Reg, message: Message) {
+        let (dst, message) = (self.reg(dst, 0), message as usize);
+        self.line(
+            format_args!("lea {}, [rel message{}]", dst, message),
+            format_args!("lea message{}(%rip), {}", message, dst),
+        );
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::super::super::corpus::PROGRAMS;
+    use super::super::super::*;
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    use std::process::{Command, Output, Stdio};
+    use std::thread;
+
+    /// A machine whose program is ready to translate.
+    pub(in super::super) type Vm<'a> = VirtualMachine<&'a [u8], &'a mut Vec<u8>>;
+
+    /// Builds each test program into an executable with `build`, which
+    /// returns `false` if its tools are missing, and checks it against the
+    /// interpreter.
+    pub(in super::super) fn assert_executables_match(
+        kind: &str,
+        build: &dyn Fn(&Vm, &Path) -> bool,
+    ) {
+        let mut cases = Vec::new();
+        for program in PROGRAMS {
+            let builder = VmBuilder::new().eof_policy(program.eof_policy);
+            cases.push((program.name, builder, program.code, program.input));
+        }
+        let edges = VmBuilder::new().tape_len(4);
+        let error = edges.clone().pointer_mode(PointerMode::Error);
+        cases.extend([
+            ("tape_wrap", edges.clone(), "+>>>+<<<[<]+.<<<<.", &b""[..]),
+            ("tape_wrap_far", edges, ">>>>>>>>>>+<<<<<<<<<<<<<<.>>.", b""),
+            ("tape_error", error.clone(), "+.>>>>+.", b""),
+            ("tape_error_mul", error.clone(), "[->>>>>+<<<<<]+.", b""),
+            ("tape_error_mul_set", error, "+[->>>>>+<<<<<]", b""),
+            (
+                "eof_error",
+                VmBuilder::new().eof_policy(EofPolicy::Error),
+                ",.,.",
+                b"x",
+            ),
+        ]);
+
+        for (name, builder, code, input) in cases {
+            let mut expected = Vec::new();
+            let mut vm = builder.build(input, &mut expected);
+            vm.compile(code).unwrap();
+            let dir = env::temp_dir().join(format!(
+                "bf-codegen-{}-{}-{}",
+                kind,
+                std::process::id(),
+                name
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            let binary = dir.join("main");
+            if !build(&vm, &binary) {
+                eprintln!("skipping {}: no toolchain", kind);
+                fs::remove_dir_all(&dir).unwrap();
+                return;
+            }
+            let result = vm.run();
+            drop(vm);
+            let output = run(&binary, input);
+            fs::remove_dir_all(&dir).unwrap();
+            assert_eq!(output.stdout, expected, "{}", name);
+            match result {
+                Ok(_) => assert!(output.status.success(), "{}", name),
+                Err(err) => {
+                    assert_eq!(output.status.code(), Some(1), "{}", name);
+                    let message = String::from_utf8(output.stderr).unwrap();
+                    assert!(err.to_string().starts_with(message.trim_end()), "{}", name);
+                }
+            }
+        }
+    }
+
+    fn run(binary: &Path, input: &[u8]) -> Output {
+        let mut command = Command::new(binary);
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // A process forked by another test may briefly hold the binary open
+        // for writing, failing with ETXTBSY.
+        let mut child = loop {
+            match command.spawn() {
+                Err(err) if err.raw_os_error() == Some(26) => thread::yield_now(),
+                child => break child.unwrap(),
+            }
+        };
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    /// Runs a command, or returns `false` if it is not installed.
+    fn tool(command: &mut Command) -> bool {
+        match command.status() {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            status => {
+                assert!(status.unwrap().success(), "{:?} failed", command);
+                true
+            }
+        }
+    }
+
+    fn build(vm: &Vm, binary: &Path, syntax: Syntax) -> bool {
+        let (source, object) = (binary.with_extension("s"), binary.with_extension("o"));
+        fs::write(&source, generate(vm, syntax).unwrap()).unwrap();
+        let mut assemble = match syntax {
+            Syntax::Nasm => Command::new("nasm"),
+            Syntax::Gas => Command::new("as"),
+        };
+        if syntax == Syntax::Nasm {
+            assemble.args(["-f", "elf64"]);
+        }
+        tool(assemble.arg("-o").arg(&object).arg(&source))
+            && tool(Command::new("ld").arg("-o").arg(binary).arg(&object))
+    }
+
+    #[test]
+    fn test_generate() {
+        let mut vm = VirtualMachine::new(io::empty(), io::sink());
+        vm.compile(",[->+<]>.").unwrap();
+        let nasm = generate(&vm, Syntax::Nasm).unwrap();
+        assert!(nasm.contains("\n    movzx ecx, byte [rbx + r12]\n"));
+        assert!(nasm.contains("\n    lea rax, [r12 + 1]\n"));
+        assert!(nasm.contains("\ntape: resb 30000\n"));
+        let gas = generate(&vm, Syntax::Gas).unwrap();
+        assert!(gas.contains("\n    movzbl (%rbx,%r12), %ecx\n"));
+        assert!(gas.contains("\n    lea 1(%r12), %rax\n"));
+        assert!(gas.contains("\ntape: .skip 30000\n"));
+    }
+
+    #[test]
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn test_gas() {
+        assert_executables_match("gas", &|vm, binary| build(vm, binary, Syntax::Gas));
+    }
+
+    #[test]
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn test_nasm() {
+        assert_executables_match("nasm", &|vm, binary| build(vm, binary, Syntax::Nasm));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        fn what<C: Cell>(vm: &VirtualMachine<io::Empty, io::Sink, C>) -> &'static str {
+            generate(vm, Syntax::Gas).unwrap_err().what
+        }
+        let vm = VmBuilder::new()
+            .cell::<u16>()
+            .build(io::empty(), io::sink());
+        assert_eq!(what(&vm), "cells wider than 8 bits");
+        let vm = VmBuilder::new()
+            .arithmetic(Arithmetic::Checked)
+            .build(io::empty(), io::sink());
+        assert_eq!(what(&vm), "checked arithmetic");
+        let vm = VmBuilder::new()
+            .pointer_mode(PointerMode::Extend)
+            .build(io::empty(), io::sink());
+        assert_eq!(what(&vm), "growing tapes");
+    }
+}
@@ -0,0 +1,307 @@
+//! C source generation.
+//!
+//! The generated program is a single C99 file with no dependencies beyond the
+//! standard library. It reads `,` from standard input, writes `.` to standard
+//! output, and exits with status 1 and a message on standard error where the
+//! interpreter would return a [`RuntimeError`](crate::bf::RuntimeError).
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+
+use super::super::{Arithmetic, Cell, EofPolicy, Instruction, PointerMode, VirtualMachine};
+use super::Unsupported;
+
+/// Translates the program compiled by `vm` into a C program using the same
+/// tape length, cell width, pointer mode, arithmetic and EOF policy.
+pub fn generate<R: Read, W: Write, C: Cell>(
+    vm: &VirtualMachine<R, W, C>,
+) -> Result<String, Unsupported> {
+    let bits = C::BITS.ok_or(Unsupported {
+        what: "unbounded cells",
+    })?;
+    let config = &vm.config;
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "\
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef uint{bits}_t cell;
+
+static cell *m;
+static size_t len = {len};
+static size_t p;
+
+static void fail(const char *message) {{
+    fflush(stdout);
+    fprintf(stderr, \"%s\\n\", message);
+    exit(1);
+}}
+",
+        bits = bits,
+        len = config.tape_len,
+    );
+
+    out.push_str(match config.pointer_mode {
+        PointerMode::Wrap => {
+            "
+static inline size_t at(long long offset) {
+    long long n = (long long)len;
+    return (size_t)((((long long)p + offset) % n + n) % n);
+}
+"
+        }
+        PointerMode::Error => {
+            "
+static inline size_t at(long long offset) {
+    long long target = (long long)p + offset;
+    if (target < 0 || target >= (long long)len) fail(\"pointer out of bounds\");
+    return (size_t)target;
+}
+"
+        }
+        PointerMode::Extend => {
+            "
+static inline size_t at(long long offset) {
+    long long target = (long long)p + offset;
+    if (target < 0) fail(\"pointer out of bounds\");
+    if ((size_t)target >= len) {
+        size_t grown = (size_t)target + 1 > 2 * len ? (size_t)target + 1 : 2 * len;
+        m = realloc(m, grown * sizeof *m);
+        if (!m) fail(\"out of memory\");
+        memset(m + len, 0, (grown - len) * sizeof *m);
+        len = grown;
+    }
+    return (size_t)target;
+}
+"
+        }
+    });
+
+    out.push_str(match config.arithmetic {
+        Arithmetic::Wrapping => {
+            "
+static inline void add(size_t i, long long delta) {
+    m[i] = (cell)(m[i] + (unsigned long long)delta);
+}
+
+static inline void mul_add(size_t i, long long factor) {
+    m[i] = (cell)(m[i] + (unsigned long long)m[p] * (unsigned long long)factor);
+}
+"
+        }
+        Arithmetic::Checked => {
+            "
+static inline void add(size_t i, long long delta) {
+    unsigned long long room = delta < 0 ? m[i] : (cell)~m[i];
+    unsigned long long magnitude = delta < 0 ? -(unsigned long long)delta : (unsigned long long)delta;
+    if (magnitude > room) fail(\"cell overflow\");
+    m[i] = (cell)(m[i] + (unsigned long long)delta);
+}
+
+static inline void mul_add(size_t i, long long factor) {
+    unsigned long long room = factor < 0 ? m[i] : (cell)~m[i];
+    unsigned long long magnitude = factor < 0 ? -(unsigned long long)factor : (unsigned long long)factor;
+    if (magnitude > room / m[p]) fail(\"cell overflow\");
+    m[i] = (cell)(m[i] + (unsigned long long)m[p] * (unsigned long long)factor);
+}
+"
+        }
+    });
+
+    let eof = match config.eof_policy {
+        EofPolicy::Unchanged => "return;",
+        EofPolicy::Zero => "m[i] = 0;",
+        EofPolicy::MinusOne => "m[i] = (cell)-1;",
+        EofPolicy::Error => "fail(\"unexpected end of input\");",
+    };
+    let _ = write!(
+        out,
+        "
+static inline void input(size_t i) {{
+    int c = getchar();
+    if (c == EOF) {{
+        if (ferror(stdin)) fail(\"failed to read input\");
+        {eof}
+    }} else {{
+        m[i] = (cell)c;
+    }}
+}}
+
+static inline void output(size_t i) {{
+    if (putchar((unsigned char)m[i]) == EOF) fail(\"failed to write output\");
+}}
+
+static inline void zero(size_t i) {{
+    m[i] = 0;
+}}
+
+int main(void) {{
+    m = calloc(len, sizeof *m);
+    if (!m) fail(\"out of memory\");
+",
+        eof = eof,
+    );
+
+    let mut depth = 1;
+    for &instruction in &vm.instructions {
+        if let Instruction::LoopEnd(_) = instruction {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        let _ = match instruction {
+            Instruction::Add { offset, delta } => {
+                writeln!(out, "{}add(at({}), {});", indent, offset, delta)
+            }
+            Instruction::Move(delta) => writeln!(out, "{}p = at({});", indent, delta),
+            Instruction::Input { offset } => writeln!(out, "{}input(at({}));", indent, offset),
+            Instruction::Output { offset } => {
+                writeln!(out, "{}output(at({}));", indent, offset)
+            }
+            Instruction::LoopStart(_) => {
+                depth += 1;
+                writeln!(out, "{}while (m[p]) {{", indent)
+            }
+            Instruction::LoopEnd(_) => writeln!(out, "{}}}", indent),
+            // `at` may move the tape, so it is called before `m` is read.
+            Instruction::SetZero { offset } => writeln!(out, "{}zero(at({}));", indent, offset),
+            Instruction::MulAdd { offset, factor } => writeln!(
+                out,
+                "{}if (m[p]) mul_add(at({}), {});",
+                indent, offset, factor
+            ),
+            Instruction::ScanRight(stride) => {
+                writeln!(out, "{}while (m[p]) p = at({});", indent, stride)
+            }
+            Instruction::ScanLeft(stride) => {
+                writeln!(out, "{}while (m[p]) p = at(-{});", indent, stride)
+            }
+        };
+    }
+    out.push_str(
+        "    if (fflush(stdout) == EOF) fail(\"failed to write output\");
+    return 0;
+}
+",
+    );
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::corpus::PROGRAMS;
+    use super::super::super::*;
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::process::{Command, Output, Stdio};
+
+    /// Compiles `source` with the system C compiler and runs it on `input`,
+    /// or returns `None` if there is no compiler.
+    fn run_c(name: &str, source: &str, input: &[u8]) -> Option<Output> {
+        let dir = env::temp_dir().join(format!("bf-codegen-c-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.c");
+        let binary = dir.join("main");
+        fs::write(&path, source).unwrap();
+        let status = Command::new("cc")
+            .args(["-std=c99", "-O1", "-Wall", "-Werror", "-o"])
+            .arg(&binary)
+            .arg(&path)
+            .status();
+        let status = match status {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            status => Some(status.unwrap()),
+        };
+        let output = status.map(|status| {
+            assert!(status.success(), "cc failed on {}", name);
+            let mut child = Command::new(&binary)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(input).unwrap();
+            child.wait_with_output().unwrap()
+        });
+        fs::remove_dir_all(&dir).unwrap();
+        output
+    }
+
+    /// Runs `code` through C and through the interpreter and compares the
+    /// output, and whether both succeeded.
+    fn assert_matches<C: Cell>(name: &str, builder: VmBuilder<C>, code: &str, input: &[u8]) {
+        let mut expected = Vec::new();
+        let mut vm = builder.build(input, &mut expected);
+        vm.compile(code).unwrap();
+        let source = generate(&vm).unwrap();
+        let result = vm.run();
+        drop(vm);
+        let Some(output) = run_c(name, &source, input) else {
+            eprintln!("skipping {}: no C compiler", name);
+            return;
+        };
+        assert_eq!(output.stdout, expected, "{}", name);
+        match result {
+            Ok(_) => assert!(output.status.success(), "{}", name),
+            Err(err) => {
+                assert_eq!(output.status.code(), Some(1), "{}", name);
+                let message = String::from_utf8(output.stderr).unwrap();
+                assert!(err.to_string().starts_with(message.trim_end()), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_corpus() {
+        for program in PROGRAMS {
+            let builder = VmBuilder::new().eof_policy(program.eof_policy);
+            assert_matches(program.name, builder, program.code, program.input);
+        }
+    }
+
+    #[test]
+    fn test_configurations() {
+        let code = "++++++++[>++++++++<-]>[<++++>-]<[[-]+.>]";
+        assert_matches("u16", VmBuilder::new().cell::<u16>(), code, b"");
+        assert_matches("u64", VmBuilder::new().cell::<u64>(), "-[->+<]>.", b"");
+        let checked = VmBuilder::new().arithmetic(Arithmetic::Checked);
+        assert_matches("checked", checked.clone(), "-", b"");
+        assert_matches(
+            "checked_mul",
+            checked,
+            "+++++[>+++++++<-]>[>++++++++<-]",
+            b"",
+        );
+        let edges = VmBuilder::new().tape_len(4);
+        assert_matches("tape_wrap", edges.clone(), "+>>>+<<<[<]+.<<<<.", b"");
+        let error = edges.clone().pointer_mode(PointerMode::Error);
+        assert_matches("tape_error", error, "+.>>>>+.", b"");
+        let extend = edges.pointer_mode(PointerMode::Extend);
+        assert_matches("tape_extend", extend.clone(), "+>>>>>>>>>+[<]<+.", b"");
+        // The tape grows while clearing or updating a cell past its end.
+        let code = ",[>>>>>>>>>>>>[-]>>>>>>>>>>>>>>>>+<<<<<<<<<<<<<<<<<<<<<<<<<<<<-]>.";
+        assert_matches("tape_extend_clear", extend, code, b"\x03");
+        let eof = VmBuilder::new().eof_policy(EofPolicy::Error);
+        assert_matches("eof_error", eof, ",.,.", b"x");
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let vm = VmBuilder::new()
+            .cell::<u32>()
+            .build(io::empty(), io::sink());
+        assert!(generate(&vm).unwrap().contains("typedef uint32_t cell;"));
+        #[cfg(feature = "bignum")]
+        {
+            let vm = VmBuilder::new()
+                .cell::<num_bigint::BigInt>()
+                .build(io::empty(), io::sink());
+            assert_eq!(generate(&vm).unwrap_err().what, "unbounded cells");
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Linux x86-64 executables.
+//!
+//! The executable holds the same machine code as the output of
+//! [`asm`](super::asm), encoded directly, so it needs no assembler or linker.
+//! It is statically linked, with the headers, error messages and code in one
+//! read-only executable segment and the tape in a zero-filled writable one.
+
+use std::io::{Read, Write};
+
+use super::super::x86::{Assembler, Cond, Label, Mem, Reg};
+use super::super::{Cell, VirtualMachine};
+use super::asm::{self, Message, Target};
+use super::Unsupported;
+
+/// Where the code segment is loaded.
+const BASE: u64 = 0x40_0000;
+/// Where the tape is mapped, clear of any code segment.
+const TAPE: u64 = 0x1_0000_0000;
+
+const HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const PROGRAM_HEADERS: u64 = 3;
+
+// Program header types and segment permissions.
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Translates the program compiled by `vm` into an ELF executable, under the
+/// same restrictions as [`asm::generate`].
+pub fn generate<R: Read, W: Write, C: Cell>(
+    vm: &VirtualMachine<R, W, C>,
+) -> Result<Vec<u8>, Unsupported> {
+    // The messages follow the headers, and the code follows the messages.
+    let mut data = Vec::new();
+    let mut messages = [0; 4];
+    let mut at = HEADER_SIZE + PROGRAM_HEADERS * PROGRAM_HEADER_SIZE;
+    for message in Message::ALL {
+        messages[message as usize] = BASE + at;
+        data.extend(message.text().as_bytes());
+        data.push(b'\n');
+        at += message.text().len() as u64 + 1;
+    }
+    let mut binary = Binary {
+        asm: Assembler::new(),
+        messages,
+    };
+    asm::lower(vm, &mut binary)?;
+    let code = binary.asm.finish();
+
+    let size = at + code.len() as u64;
+    let mut out = Vec::with_capacity(size as usize);
+    out.extend(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    out.extend(2u16.to_le_bytes()); // executable
+    out.extend(0x3Eu16.to_le_bytes()); // x86-64
+    out.extend(1u32.to_le_bytes());
+    out.extend((BASE + at).to_le_bytes()); // entry point
+    out.extend(HEADER_SIZE.to_le_bytes()); // program headers
+    out.extend(0u64.to_le_bytes()); // section headers
+    out.extend(0u32.to_le_bytes());
+    out.extend((HEADER_SIZE as u16).to_le_bytes());
+    out.extend((PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    out.extend((PROGRAM_HEADERS as u16).to_le_bytes());
+    out.extend([0; 6]);
+
+    program_header(&mut out, PT_LOAD, PF_R | PF_X, BASE, size, size);
+    let tape_len = vm.config.tape_len as u64;
+    program_header(&mut out, PT_LOAD, PF_R | PF_W, TAPE, 0, tape_len);
+    program_header(&mut out, PT_GNU_STACK, PF_R | PF_W, 0, 0, 0);
+    out.extend(data);
+    out.extend(code);
+    Ok(out)
+}
+
+fn program_header(out: &mut Vec<u8>, kind: u32, flags: u32, address: u64, file: u64, memory: u64) {
+    out.extend(kind.to_le_bytes());
+    out.extend(flags.to_le_bytes());
+    out.extend(0u64.to_le_bytes()); // offset in the file
+    out.extend(address.to_le_bytes());
+    out.extend(address.to_le_bytes());
+    out.extend(file.to_le_bytes());
+    out.extend(memory.to_le_bytes());
+    out.extend(0x1000u64.to_le_bytes());
+}
+
+/// Encodes machine code with absolute addresses for the tape and messages.
+struct Binary {
+    asm: Assembler,
+    messages: [u64; 4],
+}
+
+impl Target for Binary {
+    type Label = Label;
+
+    fn label(&mut self) -> Label {
+        self.asm.label()
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.asm.bind(label);
+    }
+
+    fn jmp(&mut self, target: Label) {
+        self.asm.jmp(target);
+    }
+
+    fn jcc(&mut self, cond: Cond, target: Label) {
+        self.asm.jcc(cond, target);
+    }
+
+    fn mov_imm(&mut self, dst: Reg, imm: u64) {
+        self.asm.mov_imm(dst, imm);
+    }
+
+    fn mov(&mut self, dst: Reg, src: Reg) {
+        self.asm.mov(dst, src);
+    }
+
+    fn lea(&mut self, dst: Reg, mem: Mem) {
+        self.asm.lea(dst, mem);
+    }
+
+    fn cmp(&mut self, a: Reg, b: Reg) {
+        self.asm.cmp(a, b);
+    }
+
+    fn test32(&mut self, a: Reg, b: Reg) {
+        self.asm.test32(a, b);
+    }
+
+    fn cqo(&mut self) {
+        self.asm.cqo();
+    }
+
+    fn idiv(&mut self, src: Reg) {
+        self.asm.idiv(src);
+    }
+
+    fn imul32(&mut self, dst: Reg, src: Reg, imm: i32) {
+        self.asm.imul32(dst, src, imm);
+    }
+
+    fn load_byte(&mut self, dst: Reg, mem: Mem) {
+        self.asm.load_byte(dst, mem);
+    }
+
+    fn add_byte(&mut self, mem: Mem, imm: u8) {
+        self.asm.add_byte(mem, imm);
+    }
+
+    fn add_byte_reg(&mut self, mem: Mem, src: Reg) {
+        self.asm.add_byte_reg(mem, src);
+    }
+
+    fn store_byte(&mut self, mem: Mem, imm: u8) {
+        self.asm.store_byte(mem, imm);
+    }
+
+    fn cmp_byte(&mut self, mem: Mem, imm: u8) {
+        self.asm.cmp_byte(mem, imm);
+    }
+
+    fn syscall(&mut self) {
+        self.asm.syscall();
+    }
+
+    fn tape(&mut self, dst: Reg) {
+        self.asm.mov_imm(dst, TAPE);
+    }
+
+    fn message(&mut self, dst: Reg, message: Message) {
+        self.asm.mov_imm(dst, self.messages[message as usize]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::*;
+    use super::super::asm::tests::assert_executables_match;
+    use super::*;
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_header() {
+        let mut vm = VirtualMachine::new(io::empty(), io::sink());
+        vm.compile("+.").unwrap();
+        let elf = generate(&vm).unwrap();
+        assert_eq!(&elf[..4], b"\x7fELF");
+        let entry = u64::from_le_bytes(elf[24..32].try_into().unwrap());
+        let code = (entry - BASE) as usize;
+        assert_eq!(&elf[code - 23..code], b"failed to write output\n");
+        assert!(elf.len() < 512);
+    }
+
+    #[test]
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn test_corpus() {
+        assert_executables_match("elf", &|vm, binary| {
+            fs::write(binary, generate(vm).unwrap()).unwrap();
+            fs::set_permissions(binary, fs::Permissions::from_mode(0o755)).unwrap();
+            true
+        });
+    }
+}
@@ -0,0 +1,547 @@
+//! LLVM IR generation.
+//!
+//! The generated module is textual IR with opaque pointers (LLVM 15 and
+//! later), defining `main` with the tape as a global array. It reads and
+//! writes through two functions, by default `getchar` and `putchar`, and
+//! uses `write`, `exit` and `fflush` from the C library to report errors the
+//! way the [C generator](super::c) does.
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+
+use super::super::{Arithmetic, Cell, EofPolicy, Instruction, PointerMode, VirtualMachine};
+use super::Unsupported;
+
+/// The functions called for `,` and `.`. The input function has the
+/// signature `i32 ()` and returns a byte, or a negative value at the end of
+/// the input. The output function has the signature `i32 (i32)` and returns
+/// a negative value if it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Io<'a> {
+    pub input: &'a str,
+    pub output: &'a str,
+}
+
+impl Default for Io<'_> {
+    fn default() -> Self {
+        Io {
+            input: "getchar",
+            output: "putchar",
+        }
+    }
+}
+
+const MESSAGES: [(&str, &str); 4] = [
+    ("bounds", "pointer out of bounds"),
+    ("eof", "unexpected end of input"),
+    ("write", "failed to write output"),
+    ("overflow", "cell overflow"),
+];
+
+/// Translates the program compiled by `vm` into an LLVM module using the
+/// same tape length, cell width, arithmetic and EOF policy, and the pointer
+/// mode unless it is [`PointerMode::Extend`].
+pub fn generate<R: Read, W: Write, C: Cell>(
+    vm: &VirtualMachine<R, W, C>,
+    io: Io,
+) -> Result<String, Unsupported> {
+    let bits = C::BITS.ok_or(Unsupported {
+        what: "unbounded cells",
+    })?;
+    let config = &vm.config;
+    if config.pointer_mode == PointerMode::Extend {
+        return Err(Unsupported {
+            what: "growing tapes",
+        });
+    }
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "@tape = internal global [{} x i{}] zeroinitializer",
+        config.tape_len, bits
+    );
+    for (name, message) in MESSAGES {
+        let _ = writeln!(
+            out,
+            "@message.{} = private constant [{} x i8] c\"{}\\0A\"",
+            name,
+            message.len() + 1,
+            message
+        );
+    }
+    let _ = write!(
+        out,
+        "
+declare i32 @{input}()
+declare i32 @{output}(i32)
+declare i64 @write(i32, ptr, i64)
+declare i32 @fflush(ptr)
+declare void @exit(i32) noreturn
+
+define internal void @fail(ptr %message, i64 %len) noreturn {{
+  %flushed = call i32 @fflush(ptr null)
+  %written = call i64 @write(i32 2, ptr %message, i64 %len)
+  call void @exit(i32 1)
+  unreachable
+}}
+
+define i32 @main() {{
+entry:
+  %p = alloca i64
+  store i64 0, ptr %p
+",
+        input = io.input,
+        output = io.output,
+    );
+
+    let mut t = Translator {
+        out,
+        temps: 0,
+        cell: format!("i{}", bits),
+        bits,
+        tape: format!("[{} x i{}]", config.tape_len, bits),
+        tape_len: config.tape_len,
+        pointer_mode: config.pointer_mode,
+        checked: config.arithmetic == Arithmetic::Checked,
+        input: io.input.into(),
+        output: io.output.into(),
+        loops: Vec::new(),
+    };
+    for &instruction in &vm.instructions {
+        t.translate(instruction, config.eof_policy);
+    }
+    let flushed = t.temp();
+    t.line(format!("{} = call i32 @fflush(ptr null)", flushed));
+    t.fail_if(&format!("icmp ne i32 {}, 0", flushed), "write");
+    t.line("ret i32 0".into());
+    for (name, message) in MESSAGES {
+        let _ = write!(
+            t.out,
+            "\nfail.{}:\n  call void @fail(ptr @message.{}, i64 {})\n  unreachable\n",
+            name,
+            name,
+            message.len() + 1
+        );
+    }
+    t.out.push_str("}\n");
+    Ok(t.out)
+}
+
+/// Emits the body of `main`, numbering temporaries and blocks as it goes.
+struct Translator {
+    out: String,
+    temps: usize,
+    /// The cell type, such as `i8`.
+    cell: String,
+    bits: u32,
+    /// The type of the tape.
+    tape: String,
+    tape_len: usize,
+    pointer_mode: PointerMode,
+    checked: bool,
+    input: String,
+    output: String,
+    /// Numbers of the open loops.
+    loops: Vec<usize>,
+}
+
+impl Translator {
+    fn line(&mut self, line: String) {
+        self.out.push_str("  ");
+        self.out.push_str(&line);
+        self.out.push('\n');
+    }
+
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("%t{}", self.temps)
+    }
+
+    fn label(&mut self, name: &str) -> String {
+        self.temps += 1;
+        format!("{}{}", name, self.temps)
+    }
+
+    fn block(&mut self, label: &str) {
+        let _ = writeln!(self.out, "{}:", label);
+    }
+
+    /// Branches to the error exit `name` if `condition` holds.
+    fn fail_if(&mut self, condition: &str, name: &str) {
+        let failed = self.temp();
+        let next = self.label("ok");
+        self.line(format!("{} = {}", failed, condition));
+        self.line(format!(
+            "br i1 {}, label %fail.{}, label %{}",
+            failed, name, next
+        ));
+        self.block(&next);
+    }
+
+    /// `value` as a constant of the cell type, reduced modulo its width.
+    fn constant(&self, value: i64) -> i64 {
+        let shift = 64 - self.bits.min(64);
+        (value << shift) >> shift
+    }
+
+    /// The index of the cell at `offset`, wrapped or checked against the
+    /// tape length.
+    fn index(&mut self, offset: isize) -> String {
+        let pointer = self.temp();
+        self.line(format!("{} = load i64, ptr %p", pointer));
+        if offset == 0 {
+            return pointer;
+        }
+        let target = self.temp();
+        self.line(format!("{} = add i64 {}, {}", target, pointer, offset));
+        match self.pointer_mode {
+            PointerMode::Wrap => {
+                let (rem, shifted, wrapped) = (self.temp(), self.temp(), self.temp());
+                let len = self.tape_len;
+                self.line(format!("{} = srem i64 {}, {}", rem, target, len));
+                self.line(format!("{} = add i64 {}, {}", shifted, rem, len));
+                self.line(format!("{} = srem i64 {}, {}", wrapped, shifted, len));
+                wrapped
+            }
+            _ => {
+                let condition = format!("icmp uge i64 {}, {}", target, self.tape_len);
+                self.fail_if(&condition, "bounds");
+                target
+            }
+        }
+    }
+
+    /// A pointer to the cell at `index`.
+    fn address(&mut self, index: &str) -> String {
+        let address = self.temp();
+        self.line(format!(
+            "{} = getelementptr inbounds {}, ptr @tape, i64 0, i64 {}",
+            address, self.tape, index
+        ));
+        address
+    }
+
+    fn load(&mut self, address: &str) -> String {
+        let value = self.temp();
+        self.line(format!("{} = load {}, ptr {}", value, self.cell, address));
+        value
+    }
+
+    fn store(&mut self, value: &str, address: &str) {
+        self.line(format!("store {} {}, ptr {}", self.cell, value, address));
+    }
+
+    fn current(&mut self) -> String {
+        let index = self.index(0);
+        let address = self.address(&index);
+        self.load(&address)
+    }
+
+    /// Converts `value` between integer types of `from` and `to` bits.
+    fn convert(&mut self, value: String, from: u32, to: u32) -> String {
+        let op = match from.cmp(&to) {
+            std::cmp::Ordering::Equal => return value,
+            std::cmp::Ordering::Less => "zext",
+            std::cmp::Ordering::Greater => "trunc",
+        };
+        let converted = self.temp();
+        self.line(format!(
+            "{} = {} i{} {} to i{}",
+            converted, op, from, value, to
+        ));
+        converted
+    }
+
+    /// Stores `value + extra` at `address`, where `extra` is an `i128`
+    /// expression, failing if the sum does not fit in a cell.
+    fn store_checked(&mut self, value: String, extra: &str, address: &str) {
+        let wide = self.convert(value, self.bits, 128);
+        let sum = self.temp();
+        self.line(format!("{} = add i128 {}, {}", sum, wide, extra));
+        let max = u128::MAX >> (128 - self.bits);
+        let condition = format!("icmp ugt i128 {}, {}", sum, max);
+        self.fail_if(&condition, "overflow");
+        let result = self.convert(sum, 128, self.bits);
+        self.store(&result, address);
+    }
+
+    fn move_pointer(&mut self, delta: isize) {
+        let target = self.index(delta);
+        self.line(format!("store i64 {}, ptr %p", target));
+    }
+
+    /// Branches to the body of loop `n` unless the current cell is zero.
+    fn test(&mut self, n: usize, body: &str) {
+        let value = self.current();
+        let zero = self.temp();
+        self.line(format!("{} = icmp eq {} {}, 0", zero, self.cell, value));
+        self.line(format!(
+            "br i1 {}, label %end{}, label %{}{}",
+            zero, n, body, n
+        ));
+    }
+
+    fn translate(&mut self, instruction: Instruction, eof_policy: EofPolicy) {
+        match instruction {
+            Instruction::Add { offset, delta } => {
+                let index = self.index(offset);
+                let address = self.address(&index);
+                let value = self.load(&address);
+                if self.checked {
+                    self.store_checked(value, &delta.to_string(), &address);
+                } else {
+                    let sum = self.temp();
+                    let delta = self.constant(delta.into());
+                    self.line(format!("{} = add {} {}, {}", sum, self.cell, value, delta));
+                    self.store(&sum, &address);
+                }
+            }
+            Instruction::Move(delta) => self.move_pointer(delta),
+            Instruction::Input { offset } => {
+                let index = self.index(offset);
+                let address = self.address(&index);
+                let (byte, eof) = (self.temp(), self.temp());
+                let (read, at_eof, done) =
+                    (self.label("read"), self.label("eof"), self.label("in"));
+                self.line(format!("{} = call i32 @{}()", byte, self.input));
+                self.line(format!("{} = icmp slt i32 {}, 0", eof, byte));
+                self.line(format!("br i1 {}, label %{}, label %{}", eof, at_eof, read));
+                self.block(&read);
+                let value = self.convert(byte, 32, self.bits);
+                self.store(&value, &address);
+                self.line(format!("br label %{}", done));
+                self.block(&at_eof);
+                match eof_policy {
+                    EofPolicy::Unchanged => {}
+                    EofPolicy::Zero => self.store("0", &address),
+                    EofPolicy::MinusOne => self.store("-1", &address),
+                    EofPolicy::Error => self.line("br label %fail.eof".into()),
+                }
+                if eof_policy != EofPolicy::Error {
+                    self.line(format!("br label %{}", done));
+                }
+                self.block(&done);
+            }
+            Instruction::Output { offset } => {
+                let index = self.index(offset);
+                let address = self.address(&index);
+                let value = self.load(&address);
+                let byte = self.convert(value, self.bits, 8);
+                let byte = self.convert(byte, 8, 32);
+                let written = self.temp();
+                self.line(format!(
+                    "{} = call i32 @{}(i32 {})",
+                    written, self.output, byte
+                ));
+                self.fail_if(&format!("icmp slt i32 {}, 0", written), "write");
+            }
+            Instruction::LoopStart(_) => {
+                let n = self.temps;
+                self.temps += 1;
+                self.loops.push(n);
+                self.test(n, "body");
+                let _ = writeln!(self.out, "body{}:", n);
+            }
+            Instruction::LoopEnd(_) => {
+                let n = self.loops.pop().expect("balanced loops");
+                self.test(n, "body");
+                let _ = writeln!(self.out, "end{}:", n);
+            }
+            Instruction::SetZero { offset } => {
+                let index = self.index(offset);
+                let address = self.address(&index);
+                self.store("0", &address);
+            }
+            Instruction::MulAdd { offset, factor } => {
+                let source = self.current();
+                let (zero, add, skip) = (self.temp(), self.label("mul"), self.label("skip"));
+                self.line(format!("{} = icmp eq {} {}, 0", zero, self.cell, source));
+                self.line(format!("br i1 {}, label %{}, label %{}", zero, skip, add));
+                self.block(&add);
+                let index = self.index(offset);
+                let address = self.address(&index);
+                let value = self.load(&address);
+                if self.checked {
+                    let wide = self.convert(source, self.bits, 128);
+                    let product = self.temp();
+                    self.line(format!("{} = mul i128 {}, {}", product, wide, factor));
+                    self.store_checked(value, &product, &address);
+                } else {
+                    let (product, sum) = (self.temp(), self.temp());
+                    let factor = self.constant(factor.into());
+                    self.line(format!(
+                        "{} = mul {} {}, {}",
+                        product, self.cell, source, factor
+                    ));
+                    self.line(format!(
+                        "{} = add {} {}, {}",
+                        sum, self.cell, value, product
+                    ));
+                    self.store(&sum, &address);
+                }
+                self.line(format!("br label %{}", skip));
+                self.block(&skip);
+            }
+            Instruction::ScanRight(stride) | Instruction::ScanLeft(stride) => {
+                let n = self.temps;
+                self.temps += 1;
+                let _ = writeln!(self.out, "  br label %scan{}\nscan{}:", n, n);
+                self.test(n, "step");
+                let _ = writeln!(self.out, "step{}:", n);
+                let stride = stride as isize;
+                self.move_pointer(match instruction {
+                    Instruction::ScanLeft(_) => -stride,
+                    _ => stride,
+                });
+                let _ = writeln!(self.out, "  br label %scan{}\nend{}:", n, n);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::corpus::PROGRAMS;
+    use super::super::super::*;
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::process::{Command, Output, Stdio};
+
+    /// Runs `module` with `lli`, linked with `extra` if given, or returns
+    /// `None` if `lli` is not installed.
+    fn run_ll(name: &str, module: &str, extra: Option<&str>, input: &[u8]) -> Option<Output> {
+        let version = match Command::new("lli").arg("--version").output() {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            output => String::from_utf8(output.unwrap().stdout).unwrap(),
+        };
+        // Opaque pointers are opt-in before LLVM 15.
+        let major: u32 = version
+            .split("LLVM version ")
+            .nth(1)
+            .and_then(|rest| rest.split('.').next())
+            .and_then(|major| major.parse().ok())
+            .unwrap_or(15);
+        let dir = env::temp_dir().join(format!("bf-codegen-llvm-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.ll");
+        fs::write(&path, module).unwrap();
+        let mut command = Command::new("lli");
+        if major < 15 {
+            command.arg("-opaque-pointers");
+        }
+        if let Some(extra) = extra {
+            let extra_path = dir.join("extra.ll");
+            fs::write(&extra_path, extra).unwrap();
+            command.arg("-extra-module").arg(extra_path);
+        }
+        let mut child = command
+            .arg(&path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        Some(output)
+    }
+
+    /// Runs `code` through LLVM and through the interpreter and compares the
+    /// output, and whether both succeeded.
+    fn assert_matches<C: Cell>(name: &str, builder: VmBuilder<C>, code: &str, input: &[u8]) {
+        let mut expected = Vec::new();
+        let mut vm = builder.build(input, &mut expected);
+        vm.compile(code).unwrap();
+        let module = generate(&vm, Io::default()).unwrap();
+        let result = vm.run();
+        drop(vm);
+        let Some(output) = run_ll(name, &module, None, input) else {
+            eprintln!("skipping {}: no lli", name);
+            return;
+        };
+        assert_eq!(output.stdout, expected, "{}", name);
+        match result {
+            Ok(_) => assert!(output.status.success(), "{}", name),
+            Err(err) => {
+                assert_eq!(output.status.code(), Some(1), "{}", name);
+                let message = String::from_utf8(output.stderr).unwrap();
+                assert!(err.to_string().starts_with(message.trim_end()), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_corpus() {
+        for program in PROGRAMS {
+            let builder = VmBuilder::new().eof_policy(program.eof_policy);
+            assert_matches(program.name, builder, program.code, program.input);
+        }
+    }
+
+    #[test]
+    fn test_configurations() {
+        let code = "++++++++[>++++++++<-]>[<++++>-]<[[-]+.>]";
+        assert_matches("u16", VmBuilder::new().cell::<u16>(), code, b"");
+        assert_matches("u64", VmBuilder::new().cell::<u64>(), "-[->+<]>.,.", b"");
+        let checked = VmBuilder::new().arithmetic(Arithmetic::Checked);
+        assert_matches("checked", checked.clone(), "-", b"");
+        assert_matches(
+            "checked_mul",
+            checked.clone(),
+            "+++++[>+++++++<-]>[>++++++++<-]",
+            b"",
+        );
+        assert_matches("checked_u64", checked.cell::<u64>(), "-[->+<]+>-", b"");
+        let edges = VmBuilder::new().tape_len(4);
+        assert_matches("tape_wrap", edges.clone(), "+>>>+<<<[<]+.<<<<.", b"");
+        assert_matches("tape_wrap_far", edges.clone(), ">>>>>>>>>>+<<.", b"");
+        let error = edges.pointer_mode(PointerMode::Error);
+        assert_matches("tape_error", error, "+.>>>>+.", b"");
+        let eof = VmBuilder::new().eof_policy(EofPolicy::Error);
+        assert_matches("eof_error", eof, ",.,.", b"x");
+    }
+
+    #[test]
+    fn test_custom_io() {
+        let mut vm = VirtualMachine::new(io::empty(), io::sink());
+        vm.compile(",[.[-],]").unwrap();
+        let io = Io {
+            input: "next",
+            output: "emit",
+        };
+        let module = generate(&vm, io).unwrap();
+        let extra = "\
+declare i32 @getchar()
+declare i32 @putchar(i32)
+
+define i32 @next() {
+  %byte = call i32 @getchar()
+  ret i32 %byte
+}
+
+define i32 @emit(i32 %byte) {
+  %next = add i32 %byte, 1
+  %result = call i32 @putchar(i32 %next)
+  ret i32 %result
+}
+";
+        if let Some(output) = run_ll("custom_io", &module, Some(extra), b"HAL") {
+            assert_eq!(output.stdout, b"IBM");
+        }
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let vm = VmBuilder::new()
+            .pointer_mode(PointerMode::Extend)
+            .build(io::empty(), io::sink());
+        assert_eq!(
+            generate(&vm, Io::default()).unwrap_err().what,
+            "growing tapes"
+        );
+    }
+}
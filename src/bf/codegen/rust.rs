@@ -0,0 +1,314 @@
+//! Rust source generation.
+//!
+//! The generated function has the signature
+//! `fn(&mut dyn Read, &mut dyn Write) -> io::Result<()>` and depends on the
+//! standard library only. Where the interpreter would return a
+//! [`RuntimeError`](crate::bf::RuntimeError), it returns an [`io::Error`]
+//! with the same message, apart from I/O errors, which are passed through.
+//!
+//! [`io::Error`]: std::io::Error
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+
+use super::super::{Arithmetic, Cell, EofPolicy, Instruction, PointerMode, VirtualMachine};
+use super::Unsupported;
+
+/// Translates the program compiled by `vm` into a Rust function called
+/// `name`, using the same tape length, cell width, pointer mode, arithmetic
+/// and EOF policy.
+pub fn generate<R: Read, W: Write, C: Cell>(
+    vm: &VirtualMachine<R, W, C>,
+    name: &str,
+) -> Result<String, Unsupported> {
+    let bits = C::BITS.ok_or(Unsupported {
+        what: "unbounded cells",
+    })?;
+    let config = &vm.config;
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "\
+#[allow(unused, clippy::all)]
+fn {name}(
+    input: &mut dyn ::std::io::Read,
+    output: &mut dyn ::std::io::Write,
+) -> ::std::io::Result<()> {{
+    use ::std::io::{{self, Read as _, Write as _}};
+
+    type Cell = u{bits};
+
+    fn fail(message: &str) -> io::Error {{
+        io::Error::new(io::ErrorKind::Other, message)
+    }}
+",
+        name = name,
+        bits = bits,
+    );
+
+    out.push_str(match config.pointer_mode {
+        PointerMode::Wrap => {
+            "
+    fn at(m: &mut Vec<Cell>, p: usize, offset: isize) -> io::Result<usize> {
+        Ok((p as isize + offset).rem_euclid(m.len() as isize) as usize)
+    }
+"
+        }
+        PointerMode::Error => {
+            "
+    fn at(m: &mut Vec<Cell>, p: usize, offset: isize) -> io::Result<usize> {
+        match p.checked_add_signed(offset) {
+            Some(target) if target < m.len() => Ok(target),
+            _ => Err(fail(\"pointer out of bounds\")),
+        }
+    }
+"
+        }
+        PointerMode::Extend => {
+            "
+    fn at(m: &mut Vec<Cell>, p: usize, offset: isize) -> io::Result<usize> {
+        let target = p
+            .checked_add_signed(offset)
+            .ok_or_else(|| fail(\"pointer out of bounds\"))?;
+        if target >= m.len() {
+            m.resize(target + 1, 0);
+        }
+        Ok(target)
+    }
+"
+        }
+    });
+
+    out.push_str(match config.arithmetic {
+        Arithmetic::Wrapping => {
+            "
+    fn add(cell: Cell, delta: i64) -> io::Result<Cell> {
+        Ok(cell.wrapping_add(delta as Cell))
+    }
+
+    fn mul_add(cell: Cell, source: Cell, factor: i64) -> io::Result<Cell> {
+        Ok(cell.wrapping_add(source.wrapping_mul(factor as Cell)))
+    }
+"
+        }
+        Arithmetic::Checked => {
+            "
+    fn add(cell: Cell, delta: i64) -> io::Result<Cell> {
+        Cell::try_from(i128::from(cell) + i128::from(delta)).map_err(|_| fail(\"cell overflow\"))
+    }
+
+    fn mul_add(cell: Cell, source: Cell, factor: i64) -> io::Result<Cell> {
+        let value = i128::from(cell) + i128::from(source) * i128::from(factor);
+        Cell::try_from(value).map_err(|_| fail(\"cell overflow\"))
+    }
+"
+        }
+    });
+
+    let eof = match config.eof_policy {
+        EofPolicy::Unchanged => "Ok(())",
+        EofPolicy::Zero => "Ok(*cell = 0)",
+        EofPolicy::MinusOne => "Ok(*cell = Cell::MAX)",
+        EofPolicy::Error => "Err(fail(\"unexpected end of input\"))",
+    };
+    let _ = write!(
+        out,
+        "
+    fn read(input: &mut dyn io::Read, cell: &mut Cell) -> io::Result<()> {{
+        let mut buf = [0; 1];
+        loop {{
+            match input.read(&mut buf) {{
+                Ok(0) => return {eof},
+                Ok(_) => return Ok(*cell = buf[0].into()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {{}}
+                Err(err) => return Err(err),
+            }}
+        }}
+    }}
+
+    let mut m: Vec<Cell> = vec![0; {len}];
+    let mut p: usize = 0;
+",
+        eof = eof,
+        len = config.tape_len,
+    );
+
+    let mut depth = 1;
+    for &instruction in &vm.instructions {
+        if let Instruction::LoopEnd(_) = instruction {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        let at = |offset: isize| format!("let i = at(&mut m, p, {})?;", offset);
+        let _ = match instruction {
+            Instruction::Add { offset, delta } => writeln!(
+                out,
+                "{}{{ {} m[i] = add(m[i], {})?; }}",
+                indent,
+                at(offset),
+                delta
+            ),
+            Instruction::Move(delta) => writeln!(out, "{}p = at(&mut m, p, {})?;", indent, delta),
+            Instruction::Input { offset } => writeln!(
+                out,
+                "{}{{ {} read(input, &mut m[i])?; }}",
+                indent,
+                at(offset)
+            ),
+            Instruction::Output { offset } => writeln!(
+                out,
+                "{}{{ {} output.write_all(&[m[i] as u8])?; }}",
+                indent,
+                at(offset)
+            ),
+            Instruction::LoopStart(_) => {
+                depth += 1;
+                writeln!(out, "{}while m[p] != 0 {{", indent)
+            }
+            Instruction::LoopEnd(_) => writeln!(out, "{}}}", indent),
+            Instruction::SetZero { offset } => {
+                writeln!(out, "{}{{ {} m[i] = 0; }}", indent, at(offset))
+            }
+            Instruction::MulAdd { offset, factor } => writeln!(
+                out,
+                "{}if m[p] != 0 {{ {} m[i] = mul_add(m[i], m[p], {})?; }}",
+                indent,
+                at(offset),
+                factor
+            ),
+            Instruction::ScanRight(stride) => writeln!(
+                out,
+                "{}while m[p] != 0 {{ p = at(&mut m, p, {})?; }}",
+                indent, stride
+            ),
+            Instruction::ScanLeft(stride) => writeln!(
+                out,
+                "{}while m[p] != 0 {{ p = at(&mut m, p, -{})?; }}",
+                indent, stride
+            ),
+        };
+    }
+    out.push_str("    Ok(())\n}\n");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::corpus::PROGRAMS;
+    use super::super::super::*;
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_generate() {
+        let mut vm = VmBuilder::new()
+            .cell::<u16>()
+            .build(io::empty(), io::sink());
+        vm.compile("+[->++<]>.").unwrap();
+        let source = generate(&vm, "double").unwrap();
+        assert!(source.starts_with("#[allow(unused, clippy::all)]\nfn double("));
+        assert!(source.contains("type Cell = u16;"));
+        assert!(source.contains("m[i] = mul_add(m[i], m[p], 2)?;"));
+    }
+
+    /// Builds all test programs into one binary with `rustc`, which runs the
+    /// program named by its argument, and checks each against the
+    /// interpreter.
+    #[test]
+    fn test_corpus() {
+        let mut cases = Vec::new();
+        for program in PROGRAMS {
+            let builder = VmBuilder::new().eof_policy(program.eof_policy);
+            cases.push((program.name, builder, program.code, program.input));
+        }
+        let edges = VmBuilder::new().tape_len(4);
+        let checked = VmBuilder::new().arithmetic(Arithmetic::Checked);
+        cases.extend([
+            ("checked", checked.clone(), "-", &b""[..]),
+            (
+                "checked_mul",
+                checked,
+                "+++++[>+++++++<-]>[>++++++++<-]",
+                b"",
+            ),
+            ("tape_wrap", edges.clone(), "+>>>+<<<[<]+.<<<<.", b""),
+            (
+                "tape_error",
+                edges.clone().pointer_mode(PointerMode::Error),
+                "+.>>>>+.",
+                b"",
+            ),
+            (
+                "tape_extend",
+                edges.pointer_mode(PointerMode::Extend),
+                "+>>>>>>>>>+[<]<+.",
+                b"",
+            ),
+            (
+                "eof_error",
+                VmBuilder::new().eof_policy(EofPolicy::Error),
+                ",.,.",
+                b"x",
+            ),
+        ]);
+
+        let mut source = String::new();
+        let mut main = String::from(
+            "fn main() {\n    let name = std::env::args().nth(1).unwrap();\n    \
+             let program = match name.as_str() {\n",
+        );
+        let mut expected = Vec::new();
+        for (name, builder, code, input) in cases {
+            let mut output = Vec::new();
+            let mut vm = builder.build(input, &mut output);
+            vm.compile(code).unwrap();
+            source.push_str(&generate(&vm, name).unwrap());
+            let result = vm.run().map_err(|err| err.to_string());
+            drop(vm);
+            expected.push((name, input, output, result));
+            main.push_str(&format!("        {:?} => {},\n", name, name));
+        }
+        main.push_str(
+            "        _ => unreachable!(),\n    };\n    \
+             if let Err(err) = program(&mut std::io::stdin(), &mut std::io::stdout()) {\n        \
+             eprintln!(\"{}\", err);\n        std::process::exit(1);\n    }\n}\n",
+        );
+        source.push_str(&main);
+
+        let dir = env::temp_dir().join(format!("bf-codegen-rust-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.rs");
+        let binary = dir.join("main");
+        fs::write(&path, source).unwrap();
+        let status = Command::new(env::var("RUSTC").unwrap_or_else(|_| "rustc".into()))
+            .args(["--edition", "2021", "-D", "warnings", "-o"])
+            .arg(&binary)
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        for (name, input, output, result) in expected {
+            let mut child = Command::new(&binary)
+                .arg(name)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(input).unwrap();
+            let actual = child.wait_with_output().unwrap();
+            assert_eq!(actual.stdout, output, "{}", name);
+            match result {
+                Ok(_) => assert!(actual.status.success(), "{}", name),
+                Err(err) => {
+                    let message = String::from_utf8(actual.stderr).unwrap();
+                    assert!(err.starts_with(message.trim_end()), "{}", name);
+                }
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,621 @@
+//! WebAssembly generation.
+//!
+//! The generated module imports two functions from `env`: `input`, of type
+//! `[] -> [i32]`, which returns the next input byte or -1 once the input is
+//! exhausted, and `output`, of type `[i32] -> []`, which writes a byte. Hosts
+//! report I/O errors by trapping. The module exports its linear memory as
+//! `memory`, holding the tape from address 0, and a function `run`, of type
+//! `[] -> [i32]`, which runs the program and returns [`OK`] or the code of the
+//! [`RuntimeErrorKind`](crate::bf::RuntimeErrorKind) the interpreter would
+//! have stopped with.
+
+use std::io::{Read, Write};
+
+use super::super::{Arithmetic, Cell, EofPolicy, Instruction, PointerMode, VirtualMachine};
+use super::Unsupported;
+
+/// Returned by `run` when the program halts.
+pub const OK: i32 = 0;
+/// Returned by `run` when the pointer leaves the tape.
+pub const POINTER_OUT_OF_BOUNDS: i32 = 1;
+/// Returned by `run` when `,` hits the end of input under
+/// [`EofPolicy::Error`].
+pub const UNEXPECTED_EOF: i32 = 2;
+/// Returned by `run` when a cell overflows under [`Arithmetic::Checked`].
+pub const CELL_OVERFLOW: i32 = 3;
+
+const PAGE: u64 = 1 << 16;
+const MAX_MEMORY: u64 = 1 << 32;
+
+// Value and block types.
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const FUNC: u8 = 0x60;
+const EMPTY: u8 = 0x40;
+
+// Opcodes.
+const UNREACHABLE: u8 = 0x00;
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const ELSE: u8 = 0x05;
+const END: u8 = 0x0b;
+const BR: u8 = 0x0c;
+const BR_IF: u8 = 0x0d;
+const RETURN: u8 = 0x0f;
+const CALL: u8 = 0x10;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const LOCAL_TEE: u8 = 0x22;
+const MEMORY_SIZE: u8 = 0x3f;
+const MEMORY_GROW: u8 = 0x40;
+const I32_CONST: u8 = 0x41;
+const I64_CONST: u8 = 0x42;
+const I32_EQ: u8 = 0x46;
+const I64_EQZ: u8 = 0x50;
+const I64_NE: u8 = 0x52;
+const I64_LT_S: u8 = 0x53;
+const I64_LT_U: u8 = 0x54;
+const I64_GT_U: u8 = 0x56;
+const I64_GE_U: u8 = 0x5a;
+const I32_SUB: u8 = 0x6b;
+const I32_MUL: u8 = 0x6c;
+const I32_AND: u8 = 0x71;
+const I64_ADD: u8 = 0x7c;
+const I64_MUL: u8 = 0x7e;
+const I64_DIV_U: u8 = 0x80;
+const I64_REM_S: u8 = 0x81;
+const I64_XOR: u8 = 0x85;
+const I64_SHR_U: u8 = 0x88;
+const I32_WRAP_I64: u8 = 0xa7;
+const I64_EXTEND_I32_S: u8 = 0xac;
+const I64_EXTEND_I32_U: u8 = 0xad;
+
+// Function indices; the imports come first.
+const INPUT: u32 = 0;
+const OUTPUT: u32 = 1;
+const RUN: u32 = 2;
+
+// Locals of `run`: the pointer and the address of the cell being accessed,
+// then the index of that cell and two temporaries.
+const P: u32 = 0;
+const I: u32 = 1;
+const T: u32 = 2;
+const V: u32 = 3;
+const S: u32 = 4;
+
+/// Translates the program compiled by `vm` into a WebAssembly module using
+/// the same tape length, cell width, pointer mode, arithmetic and EOF policy.
+pub fn generate<R: Read, W: Write, C: Cell>(
+    vm: &VirtualMachine<R, W, C>,
+) -> Result<Vec<u8>, Unsupported> {
+    let bits = C::BITS.ok_or(Unsupported {
+        what: "unbounded cells",
+    })?;
+    let config = &vm.config;
+    let size = bits / 8;
+    let bytes = (config.tape_len as u64).saturating_mul(size.into());
+    if bytes > MAX_MEMORY {
+        return Err(Unsupported {
+            what: "tapes over 4 GiB",
+        });
+    }
+
+    let mut t = Translator {
+        code: Vec::new(),
+        size,
+        mask: (u64::MAX >> (64 - bits)) as i64,
+        tape_len: config.tape_len as i64,
+        pointer_mode: config.pointer_mode,
+        checked: config.arithmetic == Arithmetic::Checked,
+        eof_policy: config.eof_policy,
+    };
+    for &instruction in &vm.instructions {
+        t.translate(instruction);
+    }
+    t.i32_const(OK);
+    t.op(END);
+
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    let mut types = Vec::new();
+    uleb(&mut types, 2);
+    types.extend([FUNC, 0, 1, I32]);
+    types.extend([FUNC, 1, I32, 0]);
+    section(&mut module, 1, &types);
+
+    let mut imports = Vec::new();
+    uleb(&mut imports, 2);
+    for (field, ty) in [("input", 0), ("output", 1)] {
+        name(&mut imports, "env");
+        name(&mut imports, field);
+        imports.extend([0, ty]);
+    }
+    section(&mut module, 2, &imports);
+
+    section(&mut module, 3, &[1, 0]);
+
+    let mut memory = vec![1, 0];
+    uleb(&mut memory, bytes.div_ceil(PAGE));
+    section(&mut module, 5, &memory);
+
+    let mut exports = Vec::new();
+    uleb(&mut exports, 2);
+    name(&mut exports, "run");
+    exports.push(0);
+    uleb(&mut exports, RUN.into());
+    name(&mut exports, "memory");
+    exports.extend([2, 0]);
+    section(&mut module, 7, &exports);
+
+    let mut body = vec![2, 2, I32, 3, I64];
+    body.extend(t.code);
+    let mut code = Vec::new();
+    uleb(&mut code, 1);
+    uleb(&mut code, body.len() as u64);
+    code.extend(body);
+    section(&mut module, 10, &code);
+    Ok(module)
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend(name.as_bytes());
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    uleb(module, contents.len() as u64);
+    module.extend(contents);
+}
+
+/// Emits the body of `run`.
+struct Translator {
+    code: Vec<u8>,
+    /// Bytes per cell.
+    size: u32,
+    /// The largest cell value, as the bits of an `i64`.
+    mask: i64,
+    tape_len: i64,
+    pointer_mode: PointerMode,
+    checked: bool,
+    eof_policy: EofPolicy,
+}
+
+impl Translator {
+    fn op(&mut self, op: u8) {
+        self.code.push(op);
+    }
+
+    /// Emits an instruction with one unsigned immediate, such as a local or
+    /// branch depth.
+    fn op_with(&mut self, op: u8, immediate: u32) {
+        self.code.push(op);
+        uleb(&mut self.code, immediate.into());
+    }
+
+    fn i32_const(&mut self, value: i32) {
+        self.code.push(I32_CONST);
+        sleb(&mut self.code, value.into());
+    }
+
+    fn i64_const(&mut self, value: i64) {
+        self.code.push(I64_CONST);
+        sleb(&mut self.code, value);
+    }
+
+    fn start(&mut self, op: u8) {
+        self.code.extend([op, EMPTY]);
+    }
+
+    /// Returns `code` from `run` if the top of the stack is nonzero.
+    fn fail_if(&mut self, code: i32) {
+        self.start(IF);
+        self.i32_const(code);
+        self.op(RETURN);
+        self.op(END);
+    }
+
+    /// Emits a load or store of a cell, given the opcodes for each width.
+    fn access(&mut self, [op8, op16, op32, op64]: [u8; 4]) {
+        let (op, align) = match self.size {
+            1 => (op8, 0),
+            2 => (op16, 1),
+            4 => (op32, 2),
+            _ => (op64, 3),
+        };
+        self.code.extend([op, align, 0]);
+    }
+
+    /// Replaces an address with the cell stored there, as an `i64`.
+    fn load(&mut self) {
+        self.access([0x31, 0x33, 0x35, 0x29]);
+    }
+
+    /// Stores a cell, given its address and then its value as an `i64`.
+    fn store(&mut self) {
+        self.access([0x3c, 0x3d, 0x3e, 0x37]);
+    }
+
+    /// Pushes the current cell.
+    fn current(&mut self) {
+        self.op_with(LOCAL_GET, P);
+        self.i32_const(self.size as i32);
+        self.op(I32_MUL);
+        self.load();
+    }
+
+    /// Sets `T` to the index of the cell at `offset` and `I` to its address,
+    /// wrapping, growing or leaving the tape as the pointer mode says.
+    fn at(&mut self, offset: isize) {
+        self.op_with(LOCAL_GET, P);
+        self.op(I64_EXTEND_I32_U);
+        if offset != 0 {
+            self.i64_const(offset as i64);
+            self.op(I64_ADD);
+        }
+        self.op_with(LOCAL_SET, T);
+        if offset != 0 {
+            match self.pointer_mode {
+                PointerMode::Wrap => {
+                    self.op_with(LOCAL_GET, T);
+                    self.i64_const(self.tape_len);
+                    self.op(I64_REM_S);
+                    self.i64_const(self.tape_len);
+                    self.op(I64_ADD);
+                    self.i64_const(self.tape_len);
+                    self.op(I64_REM_S);
+                    self.op_with(LOCAL_SET, T);
+                }
+                PointerMode::Error => {
+                    self.op_with(LOCAL_GET, T);
+                    self.i64_const(self.tape_len);
+                    self.op(I64_GE_U);
+                    self.fail_if(POINTER_OUT_OF_BOUNDS);
+                }
+                PointerMode::Extend => self.extend(),
+            }
+        }
+        self.op_with(LOCAL_GET, T);
+        self.op(I32_WRAP_I64);
+        self.i32_const(self.size as i32);
+        self.op(I32_MUL);
+        self.op_with(LOCAL_SET, I);
+    }
+
+    /// Fails if `T` is negative, and otherwise grows memory to hold it,
+    /// trapping if it cannot.
+    fn extend(&mut self) {
+        self.op_with(LOCAL_GET, T);
+        self.i64_const(0);
+        self.op(I64_LT_S);
+        self.fail_if(POINTER_OUT_OF_BOUNDS);
+
+        // V = pages needed to hold cells up to T.
+        self.op_with(LOCAL_GET, T);
+        self.i64_const(1);
+        self.op(I64_ADD);
+        self.i64_const(self.size.into());
+        self.op(I64_MUL);
+        self.i64_const(PAGE as i64 - 1);
+        self.op(I64_ADD);
+        self.i64_const(16);
+        self.op(I64_SHR_U);
+        self.op_with(LOCAL_TEE, V);
+        self.code.extend([MEMORY_SIZE, 0]);
+        self.op(I64_EXTEND_I32_U);
+        self.op(I64_GT_U);
+        self.start(IF);
+        self.op_with(LOCAL_GET, V);
+        self.i64_const((MAX_MEMORY / PAGE) as i64);
+        self.op(I64_GT_U);
+        self.start(IF);
+        self.op(UNREACHABLE);
+        self.op(END);
+        self.op_with(LOCAL_GET, V);
+        self.op(I32_WRAP_I64);
+        self.code.extend([MEMORY_SIZE, 0]);
+        self.op(I32_SUB);
+        self.code.extend([MEMORY_GROW, 0]);
+        self.i32_const(-1);
+        self.op(I32_EQ);
+        self.start(IF);
+        self.op(UNREACHABLE);
+        self.op(END);
+        self.op(END);
+    }
+
+    /// Fails with [`CELL_OVERFLOW`] unless `V` can have `magnitude` added, or
+    /// subtracted if `negative`, after the room left is divided by `S` when
+    /// `scaled`.
+    fn check_overflow(&mut self, magnitude: u32, negative: bool, scaled: bool) {
+        self.op_with(LOCAL_GET, V);
+        if !negative {
+            self.i64_const(self.mask);
+            self.op(I64_XOR);
+        }
+        if scaled {
+            self.op_with(LOCAL_GET, S);
+            self.op(I64_DIV_U);
+        }
+        self.i64_const(magnitude.into());
+        self.op(I64_LT_U);
+        self.fail_if(CELL_OVERFLOW);
+    }
+
+    /// Leaves `T` as the new pointer.
+    fn move_pointer(&mut self, delta: isize) {
+        self.at(delta);
+        self.op_with(LOCAL_GET, T);
+        self.op(I32_WRAP_I64);
+        self.op_with(LOCAL_SET, P);
+    }
+
+    /// Opens a block containing a loop that exits when the current cell is
+    /// zero; closed by [`Translator::end_loop`].
+    fn start_loop(&mut self) {
+        self.start(BLOCK);
+        self.start(LOOP);
+        self.current();
+        self.op(I64_EQZ);
+        self.op_with(BR_IF, 1);
+    }
+
+    fn end_loop(&mut self) {
+        self.op_with(BR, 0);
+        self.op(END);
+        self.op(END);
+    }
+
+    fn translate(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Add { offset, delta } => {
+                self.at(offset);
+                self.op_with(LOCAL_GET, I);
+                self.load();
+                self.op_with(LOCAL_SET, V);
+                if self.checked {
+                    self.check_overflow(delta.unsigned_abs(), delta < 0, false);
+                }
+                self.op_with(LOCAL_GET, I);
+                self.op_with(LOCAL_GET, V);
+                self.i64_const(delta.into());
+                self.op(I64_ADD);
+                self.store();
+            }
+            Instruction::Move(delta) => self.move_pointer(delta),
+            Instruction::Input { offset } => {
+                self.at(offset);
+                self.op_with(CALL, INPUT);
+                self.op(I64_EXTEND_I32_S);
+                self.op_with(LOCAL_TEE, T);
+                self.i64_const(0);
+                self.op(I64_LT_S);
+                self.start(IF);
+                match self.eof_policy {
+                    EofPolicy::Unchanged => {}
+                    EofPolicy::Zero | EofPolicy::MinusOne => {
+                        self.op_with(LOCAL_GET, I);
+                        let value = match self.eof_policy {
+                            EofPolicy::Zero => 0,
+                            _ => self.mask,
+                        };
+                        self.i64_const(value);
+                        self.store();
+                    }
+                    EofPolicy::Error => {
+                        self.i32_const(UNEXPECTED_EOF);
+                        self.op(RETURN);
+                    }
+                }
+                self.op(ELSE);
+                self.op_with(LOCAL_GET, I);
+                self.op_with(LOCAL_GET, T);
+                self.store();
+                self.op(END);
+            }
+            Instruction::Output { offset } => {
+                self.at(offset);
+                self.op_with(LOCAL_GET, I);
+                self.load();
+                self.op(I32_WRAP_I64);
+                self.i32_const(0xff);
+                self.op(I32_AND);
+                self.op_with(CALL, OUTPUT);
+            }
+            Instruction::LoopStart(_) => self.start_loop(),
+            Instruction::LoopEnd(_) => self.end_loop(),
+            Instruction::SetZero { offset } => {
+                self.at(offset);
+                self.op_with(LOCAL_GET, I);
+                self.i64_const(0);
+                self.store();
+            }
+            Instruction::MulAdd { offset, factor } => {
+                self.current();
+                self.op_with(LOCAL_TEE, S);
+                self.i64_const(0);
+                self.op(I64_NE);
+                self.start(IF);
+                self.at(offset);
+                self.op_with(LOCAL_GET, I);
+                self.load();
+                self.op_with(LOCAL_SET, V);
+                if self.checked {
+                    self.check_overflow(factor.unsigned_abs(), factor < 0, true);
+                }
+                self.op_with(LOCAL_GET, I);
+                self.op_with(LOCAL_GET, V);
+                self.op_with(LOCAL_GET, S);
+                self.i64_const(factor.into());
+                self.op(I64_MUL);
+                self.op(I64_ADD);
+                self.store();
+                self.op(END);
+            }
+            Instruction::ScanRight(stride) | Instruction::ScanLeft(stride) => {
+                let stride = stride as isize;
+                self.start_loop();
+                self.move_pointer(match instruction {
+                    Instruction::ScanLeft(_) => -stride,
+                    _ => stride,
+                });
+                self.end_loop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::corpus::PROGRAMS;
+    use super::super::super::*;
+    use super::*;
+    use std::io;
+    use wasmi::{Caller, Engine, Linker, Module, Store};
+
+    struct Host<'a> {
+        input: &'a [u8],
+        output: Vec<u8>,
+    }
+
+    /// Runs `wasm` with `wasmi`, returning the output and the result of `run`.
+    fn run_wasm(wasm: &[u8], input: &[u8]) -> (Vec<u8>, i32) {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm).unwrap();
+        let mut store = Store::new(
+            &engine,
+            Host {
+                input,
+                output: Vec::new(),
+            },
+        );
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap("env", "input", |mut caller: Caller<'_, Host>| {
+                let host = caller.data_mut();
+                match host.input.split_first() {
+                    Some((&byte, rest)) => {
+                        host.input = rest;
+                        i32::from(byte)
+                    }
+                    None => -1,
+                }
+            })
+            .unwrap();
+        linker
+            .func_wrap(
+                "env",
+                "output",
+                |mut caller: Caller<'_, Host>, byte: i32| {
+                    caller.data_mut().output.push(byte as u8);
+                },
+            )
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        let run = instance.get_typed_func::<(), i32>(&store, "run").unwrap();
+        let status = run.call(&mut store, ()).unwrap();
+        (store.into_data().output, status)
+    }
+
+    /// Runs `code` through WebAssembly and through the interpreter and
+    /// compares the output and how each stopped.
+    fn assert_matches<C: Cell>(builder: VmBuilder<C>, code: &str, input: &[u8]) {
+        let mut expected = Vec::new();
+        let mut vm = builder.build(input, &mut expected);
+        vm.compile(code).unwrap();
+        let wasm = generate(&vm).unwrap();
+        let status = match vm.run() {
+            Ok(_) => OK,
+            Err(err) => match err.kind {
+                RuntimeErrorKind::PointerOutOfBounds => POINTER_OUT_OF_BOUNDS,
+                RuntimeErrorKind::UnexpectedEof => UNEXPECTED_EOF,
+                RuntimeErrorKind::CellOverflow => CELL_OVERFLOW,
+                kind => panic!("unexpected error {:?}", kind),
+            },
+        };
+        drop(vm);
+        assert_eq!(run_wasm(&wasm, input), (expected, status), "{}", code);
+    }
+
+    #[test]
+    fn test_corpus() {
+        for program in PROGRAMS {
+            let builder = VmBuilder::new().eof_policy(program.eof_policy);
+            assert_matches(builder, program.code, program.input);
+        }
+    }
+
+    #[test]
+    fn test_configurations() {
+        let code = "++++++++[>++++++++<-]>[<++++>-]<[[-]+.>]";
+        assert_matches(VmBuilder::new().cell::<u16>(), code, b"");
+        assert_matches(VmBuilder::new().cell::<u32>(), "-[->+<]>.,.", b"");
+        assert_matches(VmBuilder::new().cell::<u64>(), "-[->+<]>.", b"");
+        let checked = VmBuilder::new().arithmetic(Arithmetic::Checked);
+        assert_matches(checked.clone(), "-", b"");
+        assert_matches(checked.clone(), "+++++[>+++++++<-]>[>++++++++<-]", b"");
+        assert_matches(checked.clone().cell::<u64>(), "-", b"");
+        assert_matches(
+            checked.cell::<u16>(),
+            "+++++[>+++++++<-]>[>++++++++<-]>.",
+            b"",
+        );
+        let edges = VmBuilder::new().tape_len(4);
+        assert_matches(edges.clone(), "+>>>+<<<[<]+.<<<<.", b"");
+        let error = edges.clone().pointer_mode(PointerMode::Error);
+        assert_matches(error.clone(), "+.>>>>+.", b"");
+        assert_matches(error, "+>+>+[<]<", b"");
+        let extend = edges.pointer_mode(PointerMode::Extend);
+        assert_matches(extend.clone(), "+>>>>>>>>>+[<]<+.", b"");
+        assert_matches(extend.clone(), "<", b"");
+        // Moves 10000 cells right, past the first page of 64-bit cells.
+        let far = "++++++++++[>++++++++++<-]>[>++++++++++<-]>[>++++++++++<-]>[[->+<]>-]+.";
+        assert_matches(extend.cell::<u64>(), far, b"");
+        let eof = VmBuilder::new().eof_policy(EofPolicy::Error);
+        assert_matches(eof, ",.,.", b"x");
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let vm = VmBuilder::new()
+            .tape_len((1 << 29) + 1)
+            .cell::<u64>()
+            .build(io::empty(), io::sink());
+        assert_eq!(generate(&vm).unwrap_err().what, "tapes over 4 GiB");
+        #[cfg(feature = "bignum")]
+        {
+            let vm = VmBuilder::new()
+                .cell::<num_bigint::BigInt>()
+                .build(io::empty(), io::sink());
+            assert_eq!(generate(&vm).unwrap_err().what, "unbounded cells");
+        }
+    }
+}
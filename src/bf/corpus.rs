@@ -0,0 +1,55 @@
+//! Programs shared by the tests that check each backend against the
+//! interpreter.
+
+use super::EofPolicy;
+
+pub(crate) struct Program {
+    pub name: &'static str,
+    pub code: &'static str,
+    pub input: &'static [u8],
+    pub eof_policy: EofPolicy,
+}
+
+impl Program {
+    const fn new(name: &'static str, code: &'static str, input: &'static [u8]) -> Program {
+        Program {
+            name,
+            code,
+            input,
+            eof_policy: EofPolicy::Zero,
+        }
+    }
+}
+
+pub(crate) const PROGRAMS: &[Program] = &[
+    Program::new(
+        "hello",
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+        b"",
+    ),
+    Program::new("digits", "++++++[>++++++++<-]>>++++++++++[<.+>-]++++++++++.", b""),
+    Program::new("cat", ",[.,]", b"cat\n"),
+    Program::new("reverse", ">,[>,]<[.<]", b"stressed"),
+    Program::new(
+        "multiply",
+        ",>,<[->[->+>+<<]>>[-<<+>>]<<<]>>.",
+        &[6, 7],
+    ),
+    Program::new("wrap", "-.<+++.>>[-]+[<<+>>-]<<.", b""),
+    Program::new("scan", "+>+>+>+>>+++<<<<<[>]>.[<]+++[>>]<.", b""),
+    Program::new("offsets", ">>+++[<++>>+<-]<.>>.<<<[-]++[>+++<-]>[>.<-]", b""),
+    Program {
+        name: "rot13",
+        code: "-,+[-[>>++++[>++++++++<-]<+<-[>+>+>-[>>>]<[[>+<-]>>+>]<<<<<-]]>>>[-]+\
+               >--[-[<->+++[-]]]<[++++++++++++<[>-[>+>>]>[+[<+>-]>+>>]<<<<<-]>>[<+>-]\
+               >[-[-<<[-]>>]<<[<<->>-]>>]<<[<<+>>-]]<[-]<.[-]<-,+]",
+        input: b"Hello, World! xyz",
+        eof_policy: EofPolicy::Unchanged,
+    },
+    Program {
+        name: "eof",
+        code: ",.,.,.",
+        input: b"a",
+        eof_policy: EofPolicy::MinusOne,
+    },
+];
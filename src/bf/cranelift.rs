@@ -0,0 +1,333 @@
+//! Translation of compiled programs to Cranelift IR, compiled to native code
+//! for the host.
+
+use std::collections::HashMap;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, SigRef, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{Linkage, Module};
+
+use super::native::{self, Callback, Code, Entry};
+use super::Instruction;
+
+/// Compiles `instructions`, or returns `None` if Cranelift does not support
+/// the host or rejects the function.
+pub(crate) fn compile(
+    instructions: &[Instruction],
+    (input, output): (Callback, Callback),
+) -> Option<Code> {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").ok()?;
+    let isa = cranelift_native::builder()
+        .ok()?
+        .finish(settings::Flags::new(flags))
+        .ok()?;
+    let mut module = JITModule::new(JITBuilder::with_isa(
+        isa,
+        cranelift_module::default_libcall_names(),
+    ));
+    let pointer_type = module.target_config().pointer_type();
+
+    let mut signature = module.make_signature();
+    signature.params.push(AbiParam::new(pointer_type));
+    signature.returns.push(AbiParam::new(types::I32));
+    let id = module
+        .declare_function("run", Linkage::Local, &signature)
+        .ok()?;
+    let mut context = module.make_context();
+    context.func.signature = signature;
+
+    let mut callback = module.make_signature();
+    callback.params.push(AbiParam::new(pointer_type));
+    callback.params.push(AbiParam::new(pointer_type));
+    callback.returns.push(AbiParam::new(types::I32));
+
+    let mut builder_context = FunctionBuilderContext::new();
+    let builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+    let mut t = Translator::new(builder, pointer_type);
+    let callback = t.builder.import_signature(callback);
+    t.translate(instructions, callback, [input as usize, output as usize])?;
+
+    module.define_function(id, &mut context).ok()?;
+    module.clear_context(&mut context);
+    module.finalize_definitions().ok()?;
+    let code = module.get_finalized_function(id);
+    // SAFETY: the function was declared with the `native` entry signature.
+    let entry = unsafe { std::mem::transmute::<*const u8, Entry>(code) };
+    Some(Code::new(entry, Box::new(Memory(Some(module)))))
+}
+
+/// Owns the memory holding compiled code, which is freed on drop.
+struct Memory(Option<JITModule>);
+
+// SAFETY: the module is not used again after compilation, only freed.
+unsafe impl Send for Memory {}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if let Some(module) = self.0.take() {
+            // SAFETY: the code is only called through the `Code` that owns
+            // this memory, which is being dropped.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    pointer_type: types::Type,
+    frame: Value,
+    tape: Variable,
+    len: Variable,
+    pointer: Variable,
+    /// Exit blocks by the index of the instruction the interpreter resumes at.
+    exits: HashMap<usize, Block>,
+}
+
+impl<'a> Translator<'a> {
+    /// Starts the function, loading the tape and pointer from the frame.
+    fn new(mut builder: FunctionBuilder<'a>, pointer_type: types::Type) -> Translator<'a> {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let frame = builder.block_params(entry)[0];
+        let (tape, len, pointer) = (
+            Variable::from_u32(0),
+            Variable::from_u32(1),
+            Variable::from_u32(2),
+        );
+        for var in [tape, len, pointer] {
+            builder.declare_var(var, pointer_type);
+        }
+        let mut t = Translator {
+            builder,
+            pointer_type,
+            frame,
+            tape,
+            len,
+            pointer,
+            exits: HashMap::new(),
+        };
+        t.reload_tape();
+        let pointer = t.load_frame(native::POINTER);
+        t.builder.def_var(t.pointer, pointer);
+        t
+    }
+
+    fn load_frame(&mut self, offset: i32) -> Value {
+        self.builder
+            .ins()
+            .load(self.pointer_type, MemFlags::trusted(), self.frame, offset)
+    }
+
+    fn store_frame(&mut self, value: Value, offset: i32) {
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, self.frame, offset);
+    }
+
+    fn reload_tape(&mut self) {
+        let tape = self.load_frame(native::TAPE);
+        let len = self.load_frame(native::LEN);
+        self.builder.def_var(self.tape, tape);
+        self.builder.def_var(self.len, len);
+    }
+
+    /// The address of the cell at the current pointer, with `offset` applied
+    /// by the load or store using it.
+    fn cell(&mut self) -> Value {
+        let tape = self.builder.use_var(self.tape);
+        let pointer = self.builder.use_var(self.pointer);
+        self.builder.ins().iadd(tape, pointer)
+    }
+
+    fn load(&mut self, offset: i32) -> Value {
+        let cell = self.cell();
+        self.builder
+            .ins()
+            .load(types::I8, MemFlags::trusted(), cell, offset)
+    }
+
+    fn store(&mut self, value: Value, offset: i32) {
+        let cell = self.cell();
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, cell, offset);
+    }
+
+    fn exit(&mut self, index: usize) -> Block {
+        let builder = &mut self.builder;
+        *self
+            .exits
+            .entry(index)
+            .or_insert_with(|| builder.create_block())
+    }
+
+    /// Continues in a new block if `pointer + offset` is on the tape, and
+    /// leaves native code at `index` otherwise. Returns `pointer + offset`.
+    fn check(&mut self, offset: i32, index: usize) -> Value {
+        let pointer = self.builder.use_var(self.pointer);
+        let target = self.builder.ins().iadd_imm(pointer, i64::from(offset));
+        let len = self.builder.use_var(self.len);
+        let outside = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, target, len);
+        let (exit, next) = (self.exit(index), self.builder.create_block());
+        self.builder.ins().brif(outside, exit, &[], next, &[]);
+        self.builder.switch_to_block(next);
+        target
+    }
+
+    fn call(
+        &mut self,
+        signature: SigRef,
+        callback: usize,
+        offset: isize,
+        index: usize,
+        fail: Block,
+    ) {
+        let pointer = self.builder.use_var(self.pointer);
+        self.store_frame(pointer, native::POINTER);
+        let index = self.builder.ins().iconst(self.pointer_type, index as i64);
+        self.store_frame(index, native::INDEX);
+        let callee = self
+            .builder
+            .ins()
+            .iconst(self.pointer_type, callback as i64);
+        let offset = self.builder.ins().iconst(self.pointer_type, offset as i64);
+        let call = self
+            .builder
+            .ins()
+            .call_indirect(signature, callee, &[self.frame, offset]);
+        let failed = self.builder.inst_results(call)[0];
+        let next = self.builder.create_block();
+        self.builder.ins().brif(failed, fail, &[], next, &[]);
+        self.builder.switch_to_block(next);
+        self.reload_tape();
+    }
+
+    fn finish(&mut self, status: u32) {
+        let pointer = self.builder.use_var(self.pointer);
+        self.store_frame(pointer, native::POINTER);
+        let status = self.builder.ins().iconst(types::I32, i64::from(status));
+        self.builder.ins().return_(&[status]);
+    }
+
+    fn translate(
+        mut self,
+        instructions: &[Instruction],
+        signature: SigRef,
+        [input, output]: [usize; 2],
+    ) -> Option<()> {
+        let fail = self.builder.create_block();
+        let mut loops = HashMap::new();
+        for (index, &instruction) in instructions.iter().enumerate() {
+            match instruction {
+                Instruction::Add { offset, delta } => {
+                    let offset = i32::try_from(offset).ok()?;
+                    self.check(offset, index);
+                    let value = self.load(offset);
+                    let value = self.builder.ins().iadd_imm(value, i64::from(delta));
+                    self.store(value, offset);
+                }
+                Instruction::Move(delta) => {
+                    let target = self.check(i32::try_from(delta).ok()?, index);
+                    self.builder.def_var(self.pointer, target);
+                }
+                Instruction::Input { offset } => self.call(signature, input, offset, index, fail),
+                Instruction::Output { offset } => self.call(signature, output, offset, index, fail),
+                Instruction::LoopStart(_) => {
+                    let (body, after) = (self.builder.create_block(), self.builder.create_block());
+                    loops.insert(index, (body, after));
+                    let value = self.load(0);
+                    self.builder.ins().brif(value, body, &[], after, &[]);
+                    self.builder.switch_to_block(body);
+                }
+                Instruction::LoopEnd(start) => {
+                    let (body, after) = loops[&start];
+                    let value = self.load(0);
+                    self.builder.ins().brif(value, body, &[], after, &[]);
+                    self.builder.switch_to_block(after);
+                }
+                Instruction::SetZero { offset } => {
+                    let offset = i32::try_from(offset).ok()?;
+                    self.check(offset, index);
+                    let zero = self.builder.ins().iconst(types::I8, 0);
+                    self.store(zero, offset);
+                }
+                Instruction::MulAdd { offset, factor } => {
+                    let offset = i32::try_from(offset).ok()?;
+                    self.check(offset, index);
+                    let source = self.load(0);
+                    let product = self.builder.ins().imul_imm(source, i64::from(factor));
+                    let value = self.load(offset);
+                    let value = self.builder.ins().iadd(value, product);
+                    self.store(value, offset);
+                }
+                Instruction::ScanRight(stride) | Instruction::ScanLeft(stride) => {
+                    let stride = i32::try_from(stride).ok()?;
+                    let stride = match instruction {
+                        Instruction::ScanLeft(_) => -stride,
+                        _ => stride,
+                    };
+                    let (top, step, done) = (
+                        self.builder.create_block(),
+                        self.builder.create_block(),
+                        self.builder.create_block(),
+                    );
+                    self.builder.ins().jump(top, &[]);
+                    self.builder.switch_to_block(top);
+                    let value = self.load(0);
+                    self.builder.ins().brif(value, step, &[], done, &[]);
+                    self.builder.switch_to_block(step);
+                    let target = self.check(stride, index);
+                    self.builder.def_var(self.pointer, target);
+                    self.builder.ins().jump(top, &[]);
+                    self.builder.switch_to_block(done);
+                }
+            }
+        }
+        self.finish(native::HALTED);
+
+        let mut exits: Vec<_> = self.exits.drain().collect();
+        exits.sort_unstable_by_key(|&(index, _)| index);
+        for (index, block) in exits {
+            self.builder.switch_to_block(block);
+            let index = self.builder.ins().iconst(self.pointer_type, index as i64);
+            self.store_frame(index, native::INDEX);
+            self.finish(native::EXITED);
+        }
+        self.builder.switch_to_block(fail);
+        self.finish(native::FAILED);
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::corpus::PROGRAMS;
+    use super::super::native;
+    use super::super::*;
+    use std::io;
+
+    #[test]
+    fn test_corpus() {
+        native::tests::assert_backend_matches(Backend::Cranelift);
+    }
+
+    #[test]
+    fn test_compiles() {
+        let mut vm = VmBuilder::new()
+            .backend(Backend::Cranelift)
+            .build(io::empty(), io::sink());
+        vm.compile(PROGRAMS[0].code).unwrap();
+        assert!(vm.native.is_some());
+    }
+}
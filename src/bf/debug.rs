@@ -0,0 +1,204 @@
+//! Stepping through a program one instruction at a time.
+//!
+//! The debugging methods continue from wherever the machine stopped, starting
+//! at the first instruction after [`VirtualMachine::compile`]. They always
+//! interpret the program, even if a native backend is configured.
+
+use std::io::{Read, Write};
+
+use super::{Cell, Instruction, RuntimeError, VirtualMachine};
+
+/// Where [`VirtualMachine::continue_until`] stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Before the first instruction at or after this byte offset in the
+    /// source, each time it is reached.
+    Source(usize),
+    /// After an instruction changes the cell at this index.
+    Cell(usize),
+}
+
+/// Why [`VirtualMachine::continue_until`] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The program ended.
+    Halted,
+    Breakpoint(Breakpoint),
+    /// The next instruction follows a `#` in the source, asking for the state
+    /// of the machine to be dumped.
+    Dump,
+}
+
+impl<R: Read, W: Write, C: Cell> VirtualMachine<R, W, C> {
+    /// Index of the next instruction to execute.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Byte offset in the source of the instruction at `index`, if the
+    /// program was compiled without optimization.
+    pub fn source_offset(&self, index: usize) -> Option<usize> {
+        self.offsets.get(index).copied()
+    }
+
+    /// Executes the next instruction, returning `false` if the program has
+    /// already ended.
+    pub fn step(&mut self) -> Result<bool, RuntimeError> {
+        let Some(&instruction) = self.instructions.get(self.index) else {
+            return Ok(false);
+        };
+        let mut room = u64::MAX;
+        self.index = self
+            .apply_profiled(instruction, &mut room)
+            .map_err(|kind| self.error(kind, self.index))?;
+        self.stats.steps += 1 + (u64::MAX - room);
+        Ok(true)
+    }
+
+    /// Executes the next instruction or, if it starts a loop, the whole loop.
+    pub fn step_over_loop(&mut self) -> Result<bool, RuntimeError> {
+        match self.instructions.get(self.index) {
+            Some(&Instruction::LoopStart(end)) => {
+                while self.index != end + 1 {
+                    self.step()?;
+                }
+                Ok(true)
+            }
+            _ => self.step(),
+        }
+    }
+
+    /// Executes instructions until the program ends, one of `breakpoints` is
+    /// hit or a `#` is reached. At least one instruction runs, so that calling
+    /// this again moves past the last stop.
+    pub fn continue_until(&mut self, breakpoints: &[Breakpoint]) -> Result<Stop, RuntimeError> {
+        let mut stops = Vec::new();
+        let mut watched = Vec::new();
+        for &breakpoint in breakpoints {
+            match breakpoint {
+                Breakpoint::Source(offset) => {
+                    if !self.offsets.is_empty() {
+                        let index = self.offsets.partition_point(|&at| at < offset);
+                        stops.push((index, breakpoint));
+                    }
+                }
+                Breakpoint::Cell(cell) => watched.push((cell, breakpoint)),
+            }
+        }
+        let mut values = Vec::with_capacity(watched.len());
+        loop {
+            values.clear();
+            values.extend(
+                watched
+                    .iter()
+                    .map(|&(cell, _)| self.memory.get(cell).cloned()),
+            );
+            if !self.step()? {
+                return Ok(Stop::Halted);
+            }
+            for (&(cell, breakpoint), value) in watched.iter().zip(&values) {
+                if self.memory.get(cell) != value.as_ref() {
+                    return Ok(Stop::Breakpoint(breakpoint));
+                }
+            }
+            if self.dumps.binary_search(&self.index).is_ok() {
+                return Ok(Stop::Dump);
+            }
+            if let Some(&(_, breakpoint)) = stops.iter().find(|&&(index, _)| index == self.index) {
+                return Ok(Stop::Breakpoint(breakpoint));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use std::io;
+
+    fn debug_vm(code: &str) -> VirtualMachine<io::Empty, Vec<u8>> {
+        let mut vm = VmBuilder::new()
+            .optimize(false)
+            .build(io::empty(), Vec::new());
+        vm.compile(code).unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = debug_vm("+\n>+");
+        assert_eq!(vm.source_offset(0), Some(0));
+        assert_eq!(vm.source_offset(1), Some(2));
+        assert!(vm.step().unwrap());
+        assert!(vm.step().unwrap());
+        assert_eq!((vm.index(), vm.pointer()), (2, 1));
+        assert!(vm.step().unwrap());
+        assert!(!vm.step().unwrap());
+        assert_eq!(vm.memory()[..2], [1, 1]);
+    }
+
+    #[test]
+    fn test_step_over_loop() {
+        let mut vm = debug_vm("+++[>++[-]<-]>+");
+        for _ in 0..3 {
+            vm.step_over_loop().unwrap();
+        }
+        assert_eq!(vm.index(), 3);
+        vm.step_over_loop().unwrap();
+        assert_eq!((vm.index(), vm.memory()[0]), (13, 0));
+        vm.step_over_loop().unwrap();
+        vm.step_over_loop().unwrap();
+        assert_eq!(vm.memory()[1], 1);
+        assert!(!vm.step_over_loop().unwrap());
+    }
+
+    #[test]
+    fn test_source_breakpoints() {
+        let mut vm = debug_vm("++[-  >+<]");
+        // The breakpoint in the comment applies to the `>` after it.
+        let breakpoints = [Breakpoint::Source(4)];
+        for remaining in [2, 1] {
+            let stop = vm.continue_until(&breakpoints).unwrap();
+            assert_eq!(stop, Stop::Breakpoint(breakpoints[0]));
+            assert_eq!((vm.index(), vm.memory()[0]), (4, remaining - 1));
+        }
+        assert_eq!(vm.continue_until(&breakpoints).unwrap(), Stop::Halted);
+        assert_eq!(vm.memory()[1], 2);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut vm = debug_vm("+>>++<<[-]");
+        let breakpoints = [Breakpoint::Cell(2), Breakpoint::Cell(30000)];
+        assert_eq!(
+            vm.continue_until(&breakpoints).unwrap(),
+            Stop::Breakpoint(Breakpoint::Cell(2))
+        );
+        assert_eq!(vm.index(), 4);
+        vm.continue_until(&breakpoints).unwrap();
+        assert_eq!(vm.memory()[2], 2);
+        assert_eq!(vm.continue_until(&breakpoints).unwrap(), Stop::Halted);
+    }
+
+    #[test]
+    fn test_dumps() {
+        let mut vm = debug_vm("+#[-#]##");
+        assert_eq!(vm.continue_until(&[]).unwrap(), Stop::Dump);
+        assert_eq!(vm.index(), 1);
+        assert_eq!(vm.continue_until(&[]).unwrap(), Stop::Dump);
+        assert_eq!(vm.index(), 3);
+        assert_eq!(vm.continue_until(&[]).unwrap(), Stop::Dump);
+        assert_eq!(vm.index(), 4);
+        assert_eq!(vm.continue_until(&[]).unwrap(), Stop::Halted);
+    }
+
+    #[test]
+    fn test_optimized_programs_have_no_positions() {
+        let mut vm = VirtualMachine::new(io::empty(), io::sink());
+        vm.compile("+#+").unwrap();
+        assert_eq!(vm.source_offset(0), None);
+        let stop = vm.continue_until(&[Breakpoint::Source(0)]).unwrap();
+        assert_eq!(stop, Stop::Halted);
+        assert_eq!(vm.memory()[0], 2);
+    }
+}
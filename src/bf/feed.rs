@@ -0,0 +1,163 @@
+//! Running programs that wait for input without blocking the caller.
+//!
+//! [`VirtualMachine::run_until_blocked`] returns as soon as `,` finds no byte
+//! available, which the input signals with [`io::ErrorKind::WouldBlock`] as
+//! non-blocking sockets do. [`InputQueue`] is such an input for bytes that are
+//! handed over with [`VirtualMachine::feed`].
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use super::{Cell, ExecutionStats, RuntimeError, RuntimeErrorKind, VirtualMachine};
+
+/// How far [`VirtualMachine::run_until_blocked`] got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The program ended.
+    Halted,
+    /// `,` is waiting for input. Running again retries it.
+    NeedsInput,
+}
+
+/// Input that is fed bytes as they arrive. Reading from an empty queue fails
+/// with [`io::ErrorKind::WouldBlock`] until the queue is closed, after which
+/// it reports the end of input.
+#[derive(Debug, Default, Clone)]
+pub struct InputQueue {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+impl InputQueue {
+    pub fn new() -> InputQueue {
+        InputQueue::default()
+    }
+
+    /// Appends `bytes` to the queue.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend(bytes);
+    }
+
+    /// Marks the end of input, once the queued bytes have been read.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+}
+
+impl Read for InputQueue {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.bytes.is_empty() && !self.closed && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.bytes.read(buf)
+    }
+}
+
+impl<R: Read, W: Write, C: Cell> VirtualMachine<R, W, C> {
+    /// Counts for the program so far, which [`run`](Self::run) resets.
+    pub fn stats(&self) -> ExecutionStats {
+        self.stats
+    }
+
+    /// Runs the program from wherever it stopped, starting at the first
+    /// instruction after [`compile`](Self::compile), until it ends or `,`
+    /// would block. The tape, pointer and instruction index are kept in
+    /// between, so calling this again once input is available continues
+    /// exactly where the program left off. The program is always
+    /// interpreted.
+    pub fn run_until_blocked(&mut self) -> Result<Status, RuntimeError> {
+        Ok(self.run_for(None)?.expect("unlimited runs end or block"))
+    }
+
+    /// Runs like [`run_until_blocked`](Self::run_until_blocked) for at most
+    /// `budget` steps, returning `None` if the budget ran out first.
+    pub(super) fn run_for(&mut self, budget: Option<u64>) -> Result<Option<Status>, RuntimeError> {
+        match self.execute(budget, false) {
+            Ok(true) => Ok(Some(Status::Halted)),
+            Ok(false) => Ok(None),
+            Err(RuntimeError {
+                kind: RuntimeErrorKind::Read(err),
+                ..
+            }) if err.kind() == io::ErrorKind::WouldBlock => Ok(Some(Status::NeedsInput)),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<W: Write, C: Cell> VirtualMachine<InputQueue, W, C> {
+    /// Queues `bytes` for `,` to read.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.push(bytes);
+    }
+
+    /// Marks the end of input, so that `,` applies the EOF policy once the
+    /// fed bytes have been read instead of waiting for more.
+    pub fn close_input(&mut self) {
+        self.input.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use std::io;
+
+    #[test]
+    fn test_input_queue() {
+        let mut queue = InputQueue::new();
+        let mut buf = [0; 4];
+        let err = queue.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        queue.push(b"abcdef");
+        assert_eq!(queue.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"abcd");
+        queue.close();
+        assert_eq!(queue.read(&mut buf).unwrap(), 2);
+        assert_eq!(queue.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_run_until_blocked() {
+        let mut output = Vec::new();
+        let mut vm = VmBuilder::new()
+            .eof_policy(EofPolicy::Zero)
+            .build(InputQueue::new(), &mut output);
+        vm.compile("+++>,[.,]<.").unwrap();
+        assert_eq!(vm.run_until_blocked().unwrap(), Status::NeedsInput);
+        assert_eq!(vm.run_until_blocked().unwrap(), Status::NeedsInput);
+        let index = vm.index();
+        vm.feed(b"ab");
+        assert_eq!(vm.run_until_blocked().unwrap(), Status::NeedsInput);
+        assert_ne!(vm.index(), index);
+        assert_eq!((vm.pointer(), vm.memory()[0]), (1, 3));
+        vm.feed(b"c");
+        vm.close_input();
+        assert_eq!(vm.run_until_blocked().unwrap(), Status::Halted);
+        assert_eq!(vm.run_until_blocked().unwrap(), Status::Halted);
+        let stats = vm.stats();
+        assert_eq!((stats.bytes_read, stats.bytes_written), (3, 4));
+        drop(vm);
+        assert_eq!(output, b"abc\x03");
+    }
+
+    #[test]
+    fn test_blocked_steps() {
+        let mut vm = VmBuilder::new()
+            .optimize(false)
+            .build(InputQueue::new(), io::sink());
+        vm.compile("+,+").unwrap();
+        assert_eq!(vm.run_until_blocked().unwrap(), Status::NeedsInput);
+        assert_eq!((vm.index(), vm.stats().steps), (1, 1));
+        vm.feed(b"\x07");
+        assert_eq!(vm.run_until_blocked().unwrap(), Status::Halted);
+        assert_eq!((vm.memory()[0], vm.stats().steps), (8, 3));
+    }
+
+    #[test]
+    fn test_blocking_run_fails() {
+        let mut vm = VirtualMachine::new(InputQueue::new(), io::sink());
+        vm.compile(",").unwrap();
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::Read(_)));
+    }
+}
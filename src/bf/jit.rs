@@ -0,0 +1,258 @@
+//! Translation of compiled programs to x86-64 machine code, run from an
+//! executable mapping.
+
+use std::collections::HashMap;
+use std::ptr;
+
+use super::native::{self, Callback, Code, Entry};
+use super::x86::{Assembler, Cond, Label, Mem, Reg};
+use super::Instruction;
+
+// Register assignment; all of these are callee-saved, so they survive the
+// I/O callbacks.
+const TAPE: Reg = Reg::Rbx;
+const POINTER: Reg = Reg::R12;
+const FRAME: Reg = Reg::R13;
+const LEN: Reg = Reg::R14;
+
+/// Translates `instructions`, or returns `None` if an offset does not fit an
+/// x86 displacement or the code cannot be mapped.
+pub(crate) fn compile(
+    instructions: &[Instruction],
+    (input, output): (Callback, Callback),
+) -> Option<Code> {
+    let code = translate(instructions, input as usize as u64, output as usize as u64)?;
+    let memory = Mapping::new(&code)?;
+    // SAFETY: the mapping holds a function following the `native` ABI.
+    let entry = unsafe { std::mem::transmute::<*mut libc::c_void, Entry>(memory.ptr) };
+    Some(Code::new(entry, Box::new(memory)))
+}
+
+fn cell(offset: i32) -> Mem {
+    Mem::indexed(TAPE, POINTER, offset)
+}
+
+struct Translator {
+    asm: Assembler,
+    /// Exit stubs by the index of the instruction the interpreter resumes at.
+    exits: HashMap<usize, Label>,
+    /// Labels after each `LoopStart` and `LoopEnd`, by the index of the start.
+    loops: HashMap<usize, (Label, Label)>,
+}
+
+impl Translator {
+    fn exit(&mut self, index: usize) -> Label {
+        let asm = &mut self.asm;
+        *self.exits.entry(index).or_insert_with(|| asm.label())
+    }
+
+    /// Leaves native code at `index` unless `pointer + offset` is on the tape.
+    fn check(&mut self, offset: i32, index: usize) {
+        if offset != 0 {
+            let exit = self.exit(index);
+            self.asm.lea(Reg::Rax, Mem::new(POINTER, offset));
+            self.asm.cmp(Reg::Rax, LEN);
+            self.asm.jcc(Cond::Ae, exit);
+        }
+    }
+
+    /// Moves the pointer by `delta`, leaving native code at `index` instead if
+    /// that would leave the tape.
+    fn move_pointer(&mut self, delta: i32, index: usize) {
+        let exit = self.exit(index);
+        self.asm.lea(Reg::Rax, Mem::new(POINTER, delta));
+        self.asm.cmp(Reg::Rax, LEN);
+        self.asm.jcc(Cond::Ae, exit);
+        self.asm.mov(POINTER, Reg::Rax);
+    }
+
+    fn call(&mut self, callback: u64, offset: isize, index: usize, fail: Label) {
+        let asm = &mut self.asm;
+        asm.store(Mem::new(FRAME, native::POINTER), POINTER);
+        asm.store_imm(Mem::new(FRAME, native::INDEX), index as i32);
+        asm.mov(Reg::Rdi, FRAME);
+        asm.mov_imm(Reg::Rsi, offset as u64);
+        asm.mov_imm(Reg::Rax, callback);
+        asm.call(Reg::Rax);
+        asm.test32(Reg::Rax, Reg::Rax);
+        asm.jcc(Cond::Ne, fail);
+        asm.load(TAPE, Mem::new(FRAME, native::TAPE));
+        asm.load(LEN, Mem::new(FRAME, native::LEN));
+    }
+}
+
+fn translate(instructions: &[Instruction], input: u64, output: u64) -> Option<Vec<u8>> {
+    // Indices are stored as 32-bit immediates.
+    i32::try_from(instructions.len()).ok()?;
+    let mut t = Translator {
+        asm: Assembler::new(),
+        exits: HashMap::new(),
+        loops: HashMap::new(),
+    };
+    let epilogue = t.asm.label();
+    let fail = t.asm.label();
+
+    // Five pushes keep the stack 16-byte aligned for the callbacks.
+    for reg in [Reg::Rbx, Reg::R12, Reg::R13, Reg::R14, Reg::R15] {
+        t.asm.push(reg);
+    }
+    t.asm.mov(FRAME, Reg::Rdi);
+    t.asm.load(TAPE, Mem::new(FRAME, native::TAPE));
+    t.asm.load(LEN, Mem::new(FRAME, native::LEN));
+    t.asm.load(POINTER, Mem::new(FRAME, native::POINTER));
+
+    for (index, &instruction) in instructions.iter().enumerate() {
+        match instruction {
+            Instruction::Add { offset, delta } => {
+                let offset = i32::try_from(offset).ok()?;
+                t.check(offset, index);
+                t.asm.add_byte(cell(offset), delta as u8);
+            }
+            Instruction::Move(delta) => t.move_pointer(i32::try_from(delta).ok()?, index),
+            Instruction::Input { offset } => t.call(input, offset, index, fail),
+            Instruction::Output { offset } => t.call(output, offset, index, fail),
+            Instruction::LoopStart(_) => {
+                let (body, after) = (t.asm.label(), t.asm.label());
+                t.loops.insert(index, (body, after));
+                t.asm.cmp_byte(cell(0), 0);
+                t.asm.jcc(Cond::E, after);
+                t.asm.bind(body);
+            }
+            Instruction::LoopEnd(start) => {
+                let (body, after) = t.loops[&start];
+                t.asm.cmp_byte(cell(0), 0);
+                t.asm.jcc(Cond::Ne, body);
+                t.asm.bind(after);
+            }
+            Instruction::SetZero { offset } => {
+                let offset = i32::try_from(offset).ok()?;
+                t.check(offset, index);
+                t.asm.store_byte(cell(offset), 0);
+            }
+            Instruction::MulAdd { offset, factor } => {
+                let offset = i32::try_from(offset).ok()?;
+                t.check(offset, index);
+                t.asm.load_byte(Reg::Rax, cell(0));
+                t.asm.imul32(Reg::Rax, Reg::Rax, factor);
+                t.asm.add_byte_reg(cell(offset), Reg::Rax);
+            }
+            Instruction::ScanRight(stride) | Instruction::ScanLeft(stride) => {
+                let stride = i32::try_from(stride).ok()?;
+                let stride = match instruction {
+                    Instruction::ScanLeft(_) => -stride,
+                    _ => stride,
+                };
+                let (top, done) = (t.asm.label(), t.asm.label());
+                t.asm.bind(top);
+                t.asm.cmp_byte(cell(0), 0);
+                t.asm.jcc(Cond::E, done);
+                t.move_pointer(stride, index);
+                t.asm.jmp(top);
+                t.asm.bind(done);
+            }
+        }
+    }
+
+    t.asm.mov_imm(Reg::Rax, native::HALTED.into());
+    t.asm.jmp(epilogue);
+    let mut exits: Vec<_> = t.exits.into_iter().collect();
+    exits.sort_unstable_by_key(|&(index, _)| index);
+    for (index, label) in exits {
+        t.asm.bind(label);
+        t.asm
+            .store_imm(Mem::new(FRAME, native::INDEX), index as i32);
+        t.asm.mov_imm(Reg::Rax, native::EXITED.into());
+        t.asm.jmp(epilogue);
+    }
+    t.asm.bind(fail);
+    t.asm.mov_imm(Reg::Rax, native::FAILED.into());
+    t.asm.bind(epilogue);
+    t.asm.store(Mem::new(FRAME, native::POINTER), POINTER);
+    for reg in [Reg::R15, Reg::R14, Reg::R13, Reg::R12, Reg::Rbx] {
+        t.asm.pop(reg);
+    }
+    t.asm.ret();
+    Some(t.asm.finish())
+}
+
+/// An executable copy of some machine code.
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the mapping is never written after it is made executable.
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(code: &[u8]) -> Option<Mapping> {
+        let len = code.len();
+        // SAFETY: a fresh private mapping is written before it is made
+        // executable, and unmapped again on failure.
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return None;
+            }
+            let mapping = Mapping { ptr, len };
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr.cast(), len);
+            if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return None;
+            }
+            Some(mapping)
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created by `Mapping::new` and nothing
+        // refers to it any more.
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::corpus::PROGRAMS;
+    use super::super::native;
+    use super::super::*;
+    use std::io;
+
+    #[test]
+    fn test_corpus() {
+        native::tests::assert_backend_matches(Backend::Jit);
+    }
+
+    #[test]
+    fn test_compiles() {
+        let mut vm = VmBuilder::new()
+            .backend(Backend::Jit)
+            .build(io::empty(), io::sink());
+        vm.compile(PROGRAMS[0].code).unwrap();
+        assert!(vm.native.is_some());
+    }
+
+    #[test]
+    fn test_fallback() {
+        // Wider cells run on the interpreter.
+        let mut output = Vec::new();
+        let mut vm = VmBuilder::new()
+            .backend(Backend::Jit)
+            .cell::<u16>()
+            .build(io::empty(), &mut output);
+        vm.compile(PROGRAMS[0].code).unwrap();
+        assert!(vm.native.is_none());
+        vm.run().unwrap();
+        assert_eq!(output, b"Hello World!\n");
+    }
+}
@@ -0,0 +1,260 @@
+//! The interface between the interpreter and machine code produced by the
+//! native backends.
+//!
+//! Compiled programs are functions taking a [`Frame`] and returning one of the
+//! `HALTED`, `EXITED` or `FAILED` statuses. The tape is a plain byte array,
+//! and `,` and `.` call back into [`VirtualMachine::apply`] so that I/O, EOF
+//! handling and error reporting are shared with the interpreter. Whenever
+//! native code would leave the tape it exits instead, and the interpreter
+//! finishes the program from that instruction.
+
+use std::any::{Any, TypeId};
+use std::ffi::c_void;
+use std::io::{Read, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+
+use super::VirtualMachine;
+use super::{Arithmetic, Backend, Cell, Config, Instruction, RuntimeError, RuntimeErrorKind};
+
+/// The program ran to completion.
+pub(crate) const HALTED: u32 = 0;
+/// Native code cannot continue; the interpreter resumes at `Frame::index`.
+pub(crate) const EXITED: u32 = 1;
+/// An I/O callback failed with `Frame::error` at `Frame::index`.
+pub(crate) const FAILED: u32 = 2;
+
+/// State shared between native code and the I/O callbacks.
+#[repr(C)]
+pub(crate) struct Frame {
+    /// Start of the tape. Reloaded after every callback, which may grow it.
+    pub tape: *mut u8,
+    pub len: usize,
+    pub pointer: usize,
+    /// Index of the current instruction, stored before every callback and
+    /// on exit.
+    pub index: usize,
+    vm: *mut c_void,
+    error: Option<RuntimeErrorKind>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+pub(crate) const TAPE: i32 = mem::offset_of!(Frame, tape) as i32;
+pub(crate) const LEN: i32 = mem::offset_of!(Frame, len) as i32;
+pub(crate) const POINTER: i32 = mem::offset_of!(Frame, pointer) as i32;
+pub(crate) const INDEX: i32 = mem::offset_of!(Frame, index) as i32;
+
+/// Signature of a compiled program.
+pub(crate) type Entry = unsafe extern "C" fn(*mut Frame) -> u32;
+
+/// Signature of the `,` and `.` callbacks, which take the offset of the cell
+/// and return zero on success.
+pub(crate) type Callback = extern "C" fn(*mut Frame, isize) -> u32;
+
+/// A compiled program and whatever owns the memory holding its code.
+pub(crate) struct Code {
+    pub entry: Entry,
+    _memory: Box<dyn Any + Send>,
+}
+
+impl Code {
+    pub fn new(entry: Entry, memory: Box<dyn Any + Send>) -> Code {
+        Code {
+            entry,
+            _memory: memory,
+        }
+    }
+}
+
+/// The callbacks for `,` and `.` on machines of type `VirtualMachine<R, W, C>`.
+pub(crate) fn callbacks<R: Read, W: Write, C: Cell>() -> (Callback, Callback) {
+    (input::<R, W, C>, output::<R, W, C>)
+}
+
+extern "C" fn input<R: Read, W: Write, C: Cell>(frame: *mut Frame, offset: isize) -> u32 {
+    callback::<R, W, C>(frame, Instruction::Input { offset })
+}
+
+extern "C" fn output<R: Read, W: Write, C: Cell>(frame: *mut Frame, offset: isize) -> u32 {
+    callback::<R, W, C>(frame, Instruction::Output { offset })
+}
+
+fn callback<R: Read, W: Write, C: Cell>(frame: *mut Frame, instruction: Instruction) -> u32 {
+    // SAFETY: native code passes on the frame it was called with, whose `vm`
+    // points at the machine running it.
+    let frame = unsafe { &mut *frame };
+    let vm = unsafe { &mut *frame.vm.cast::<VirtualMachine<R, W, C>>() };
+    vm.pointer = frame.pointer;
+    vm.index = frame.index;
+    // Native code cannot be limited, so scans run to the end.
+    let mut room = u64::MAX;
+    // Unwinding into native code is undefined, so panics are carried across.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| vm.apply(instruction, &mut room)));
+    frame.tape = vm.memory.as_mut_ptr().cast();
+    frame.len = vm.memory.len();
+    match result {
+        Ok(Ok(_)) => 0,
+        Ok(Err(kind)) => {
+            frame.error = Some(kind);
+            1
+        }
+        Err(payload) => {
+            frame.panic = Some(payload);
+            1
+        }
+    }
+}
+
+/// Compiles `instructions` with the backend selected in `config`, if it
+/// supports the configuration and the target.
+#[allow(unused_variables)]
+pub(crate) fn compile<R: Read, W: Write, C: Cell>(
+    instructions: &[Instruction],
+    config: &Config,
+) -> Option<Code> {
+    // Native code works on wrapping bytes only, and cannot be interrupted or
+    // profiled.
+    if TypeId::of::<C>() != TypeId::of::<u8>()
+        || config.arithmetic != Arithmetic::Wrapping
+        || config.step_limit.is_some()
+        || config.cancel.is_some()
+        || config.profile
+    {
+        return None;
+    }
+    match config.backend {
+        Backend::Interpreter => None,
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        Backend::Jit => super::jit::compile(instructions, callbacks::<R, W, C>()),
+        #[cfg(feature = "cranelift")]
+        Backend::Cranelift => super::cranelift::compile(instructions, callbacks::<R, W, C>()),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+impl<R: Read, W: Write, C: Cell> VirtualMachine<R, W, C> {
+    /// Runs `entry` from the first instruction, leaving the index where the
+    /// interpreter has to take over.
+    pub(super) fn run_native(&mut self, entry: Entry) -> Result<(), RuntimeError> {
+        let mut frame = Frame {
+            tape: self.memory.as_mut_ptr().cast(),
+            len: self.memory.len(),
+            pointer: self.pointer,
+            index: 0,
+            vm: (self as *mut VirtualMachine<R, W, C>).cast(),
+            error: None,
+            panic: None,
+        };
+        // SAFETY: `compile` only produces code for byte tapes, and the frame
+        // describes this machine's tape.
+        let status = unsafe { entry(&mut frame) };
+        if let Some(payload) = frame.panic {
+            panic::resume_unwind(payload);
+        }
+        self.pointer = frame.pointer;
+        match status {
+            HALTED => self.index = self.instructions.len(),
+            EXITED => self.index = frame.index,
+            _ => {
+                let kind = frame.error.expect("native code failed without an error");
+                return Err(self.error(kind, frame.index));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Differential tests shared by the native backends.
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::super::corpus::PROGRAMS;
+    use super::super::*;
+    use std::io;
+
+    /// Programs that run off either end of a four-cell tape, to be run in every
+    /// pointer mode.
+    const TAPE_EDGES: &[&str] = &[
+        "<+.",
+        "+>>>+<<<[<]",
+        "+>>>>>+.",
+        "+[<]",
+        "+>+>+>++[->>+<<]>>.",
+        ",>,.<<.",
+    ];
+
+    type Outcome = (
+        Result<ExecutionStats, RuntimeError>,
+        Vec<u8>,
+        Vec<u8>,
+        usize,
+    );
+
+    fn run(builder: VmBuilder, code: &str, input: &[u8]) -> Outcome {
+        let mut output = Vec::new();
+        let mut vm = builder.build(input, &mut output);
+        vm.compile(code).unwrap();
+        let result = vm.run();
+        let (memory, pointer) = (vm.memory().to_vec(), vm.pointer());
+        (result, output, memory, pointer)
+    }
+
+    /// Runs `code` on `backend` and on the interpreter and compares everything
+    /// observable except the step count.
+    fn assert_matches(backend: Backend, builder: VmBuilder, code: &str, input: &[u8]) {
+        let (expected, expected_output, expected_memory, expected_pointer) =
+            run(builder.clone(), code, input);
+        let (actual, output, memory, pointer) = run(builder.backend(backend), code, input);
+        assert_eq!(output, expected_output, "{}", code);
+        assert_eq!(memory, expected_memory, "{}", code);
+        assert_eq!(pointer, expected_pointer, "{}", code);
+        match (expected, actual) {
+            (Ok(expected), Ok(actual)) => {
+                assert_eq!(actual.bytes_read, expected.bytes_read, "{}", code);
+                assert_eq!(actual.bytes_written, expected.bytes_written, "{}", code);
+            }
+            (Err(expected), Err(actual)) => {
+                assert_eq!(actual.to_string(), expected.to_string(), "{}", code)
+            }
+            (expected, actual) => panic!("{}: {:?} != {:?}", code, actual, expected),
+        }
+    }
+
+    /// Checks that `backend` behaves like the interpreter on the corpus, at the
+    /// edges of the tape and when I/O fails.
+    pub(crate) fn assert_backend_matches(backend: Backend) {
+        for program in PROGRAMS {
+            let builder = VmBuilder::new().eof_policy(program.eof_policy);
+            assert_matches(backend, builder.clone(), program.code, program.input);
+            assert_matches(
+                backend,
+                builder.optimize(false),
+                program.code,
+                program.input,
+            );
+        }
+        for mode in [PointerMode::Wrap, PointerMode::Error, PointerMode::Extend] {
+            let builder = VmBuilder::new().tape_len(4).pointer_mode(mode);
+            for code in TAPE_EDGES {
+                assert_matches(backend, builder.clone(), code, b"ab");
+            }
+        }
+        let builder = VmBuilder::new().eof_policy(EofPolicy::Error);
+        assert_matches(backend, builder, "+++[>,.<-]", b"ab");
+
+        struct Broken;
+        impl io::Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut vm = VmBuilder::new().backend(backend).build(io::empty(), Broken);
+        vm.compile("++>+.").unwrap();
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::Write(_)));
+        assert_eq!((err.index, err.pointer), (2, 0));
+    }
+}
@@ -0,0 +1,734 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::Instruction;
+
+/// Properties of the machine a program will run on. Rewrites that would hide
+/// an overflow or an out-of-bounds move are only made when these allow it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Target {
+    /// Cell arithmetic never fails, so `+-` may cancel out.
+    pub wrapping: bool,
+    /// Width of a cell, if fixed. Fixed-width cells are unsigned, so
+    /// decrementing always reaches zero.
+    pub bits: Option<u32>,
+    /// Pointer moves never fail, so `><` may cancel out.
+    pub pointer_wraps: bool,
+    /// Initial number of cells on the tape.
+    pub tape_len: usize,
+    /// The program starts on a zeroed tape, rather than wherever an earlier
+    /// program left off.
+    pub zeroed: bool,
+}
+
+/// Runs every optimization pass over a parsed program.
+pub(crate) fn optimize(instructions: &[Instruction], target: Target) -> Vec<Instruction> {
+    let instructions = fold(instructions, target);
+    let instructions = idioms(&instructions, target);
+    let instructions = offsets(&instructions, target);
+    eliminate_dead_code(&instructions, target)
+}
+
+/// Merges runs of `Add` and `Move` into single instructions, dropping runs
+/// that cancel out.
+pub(crate) fn fold(instructions: &[Instruction], target: Target) -> Vec<Instruction> {
+    let mut folded = Vec::with_capacity(instructions.len());
+    for &instruction in instructions {
+        push_folded(&mut folded, instruction, target);
+    }
+    link(&mut folded);
+    folded
+}
+
+/// Pushes `instruction`, merging it into the previous one where possible.
+fn push_folded(folded: &mut Vec<Instruction>, instruction: Instruction, target: Target) {
+    let merged = match (folded.last_mut(), instruction) {
+        (
+            Some(Instruction::Add {
+                offset,
+                delta: total,
+            }),
+            Instruction::Add {
+                offset: next,
+                delta,
+            },
+        ) if *offset == next && (target.wrapping || (*total < 0) == (delta < 0)) => {
+            total.checked_add(delta).map(|sum| *total = sum)
+        }
+        (Some(Instruction::Move(total)), Instruction::Move(delta))
+            if target.pointer_wraps || (*total < 0) == (delta < 0) =>
+        {
+            total.checked_add(delta).map(|sum| *total = sum)
+        }
+        _ => None,
+    };
+    match merged {
+        Some(()) => {
+            if let Some(Instruction::Add { delta: 0, .. } | Instruction::Move(0)) = folded.last() {
+                folded.pop();
+            }
+        }
+        None => folded.push(instruction),
+    }
+}
+
+/// Replaces clear, multiply and scan loops with dedicated instructions.
+pub(crate) fn idioms(instructions: &[Instruction], target: Target) -> Vec<Instruction> {
+    let mut rewritten = Vec::with_capacity(instructions.len());
+    let mut index = 0;
+    while index < instructions.len() {
+        if let Instruction::LoopStart(end) = instructions[index] {
+            if let Some(replacement) = recognize(&instructions[index + 1..end], target) {
+                rewritten.extend(replacement);
+                index = end + 1;
+                continue;
+            }
+        }
+        rewritten.push(instructions[index]);
+        index += 1;
+    }
+    link(&mut rewritten);
+    rewritten
+}
+
+fn recognize(body: &[Instruction], target: Target) -> Option<Vec<Instruction>> {
+    match *body {
+        [Instruction::Add {
+            offset: 0,
+            delta: -1,
+        }] if target.bits.is_some() => Some(vec![Instruction::SetZero { offset: 0 }]),
+        [Instruction::Add {
+            offset: 0,
+            delta: 1,
+        }] if target.bits.is_some() && target.wrapping => {
+            Some(vec![Instruction::SetZero { offset: 0 }])
+        }
+        [Instruction::Move(stride)] if stride > 0 => {
+            Some(vec![Instruction::ScanRight(stride.unsigned_abs())])
+        }
+        [Instruction::Move(stride)] => Some(vec![Instruction::ScanLeft(stride.unsigned_abs())]),
+        _ => multiply(body, target),
+    }
+}
+
+/// Recognizes balanced loops that decrement the current cell once per
+/// iteration and add constant multiples of it to other cells.
+fn multiply(body: &[Instruction], target: Target) -> Option<Vec<Instruction>> {
+    target.bits?;
+    let mut offset: isize = 0;
+    let (mut lowest, mut highest) = (0, 0);
+    let mut deltas: BTreeMap<isize, i64> = BTreeMap::new();
+    for instruction in body {
+        match *instruction {
+            Instruction::Add { offset: at, delta } => {
+                let total = deltas.entry(offset + at).or_default();
+                // Without wrapping, mixed signs could overflow midway through
+                // an iteration even though the net change fits.
+                if !target.wrapping && *total != 0 && (*total < 0) != (delta < 0) {
+                    return None;
+                }
+                *total += i64::from(delta);
+            }
+            Instruction::Move(delta) => {
+                offset = offset.checked_add(delta)?;
+                lowest = lowest.min(offset);
+                highest = highest.max(offset);
+            }
+            _ => return None,
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+    // On a wrapping tape, a target a whole tape length away is the counter
+    // itself, and the loop may never end.
+    if target.pointer_wraps && deltas.keys().any(|at| at.unsigned_abs() >= target.tape_len) {
+        return None;
+    }
+    // With wrapping cells, counting up from `v` to zero takes `-v` steps.
+    let sign = match deltas.remove(&0) {
+        Some(-1) => 1,
+        Some(1) if target.wrapping => -1,
+        _ => return None,
+    };
+    let mut rewritten = Vec::with_capacity(deltas.len() + 1);
+    for (offset, delta) in deltas {
+        if delta != 0 {
+            let factor = i32::try_from(delta * sign).ok()?;
+            rewritten.push(Instruction::MulAdd { offset, factor });
+        }
+    }
+    rewritten.push(Instruction::SetZero { offset: 0 });
+    // As in `Block::flush`, the rewrite must still reach the furthest
+    // positions the pointer visits unless the pointer wraps.
+    if !target.pointer_wraps {
+        let mut reached = (0, 0);
+        for instruction in &rewritten {
+            if let Instruction::MulAdd { offset, .. } = *instruction {
+                reached = (reached.0.min(offset), reached.1.max(offset));
+            }
+        }
+        if reached != (lowest, highest) {
+            return None;
+        }
+    }
+    Some(rewritten)
+}
+
+/// Rewrites each straight-line run of cell operations to address cells
+/// relative to where the run started, followed by a single `Move`.
+pub(crate) fn offsets(instructions: &[Instruction], target: Target) -> Vec<Instruction> {
+    let mut rewritten = Vec::with_capacity(instructions.len());
+    let mut block = Block::default();
+    for &instruction in instructions {
+        if !block.push(instruction, target) {
+            block.flush(&mut rewritten, target);
+            rewritten.push(instruction);
+        }
+    }
+    block.flush(&mut rewritten, target);
+    link(&mut rewritten);
+    rewritten
+}
+
+#[derive(Default)]
+struct Block {
+    /// The instructions as given, kept in case the rewrite has to be undone.
+    original: Vec<Instruction>,
+    rewritten: Vec<Instruction>,
+    /// Pointer position relative to the start of the block.
+    offset: isize,
+    /// Furthest positions the pointer visits within the block.
+    lowest: isize,
+    highest: isize,
+    /// Furthest cells accessed by the first `settled` rewritten instructions,
+    /// which later ones can no longer fold into.
+    accessed: (isize, isize),
+    settled: usize,
+    /// Whether input or output would come before a move that can fail.
+    reordered: bool,
+}
+
+impl Block {
+    /// Adds `instruction` to the block, or returns false if it cannot be part
+    /// of one.
+    fn push(&mut self, instruction: Instruction, target: Target) -> bool {
+        let shifted = match instruction {
+            Instruction::Move(delta) => {
+                self.offset += delta;
+                self.lowest = self.lowest.min(self.offset);
+                self.highest = self.highest.max(self.offset);
+                None
+            }
+            Instruction::Add { offset, delta } => Some(Instruction::Add {
+                offset: self.offset + offset,
+                delta,
+            }),
+            Instruction::Input { offset } => Some(Instruction::Input {
+                offset: self.offset + offset,
+            }),
+            Instruction::Output { offset } => Some(Instruction::Output {
+                offset: self.offset + offset,
+            }),
+            Instruction::SetZero { offset } => Some(Instruction::SetZero {
+                offset: self.offset + offset,
+            }),
+            _ => return false,
+        };
+        self.original.push(instruction);
+        if let Some(shifted) = shifted {
+            push_folded(&mut self.rewritten, shifted, target);
+        }
+        if let Instruction::Input { .. } | Instruction::Output { .. } = instruction {
+            // Unless the pointer wraps, a move out of bounds before the input
+            // or output has to fail before it, so the rewrite must already
+            // have accessed the furthest cells by then.
+            self.accessed = Self::reach(self.accessed, &self.rewritten[self.settled..]);
+            self.settled = self.rewritten.len();
+            self.reordered |= self.accessed != (self.lowest, self.highest);
+        }
+        true
+    }
+
+    /// Widens `range` to take in the cells accessed by `instructions`.
+    fn reach(mut range: (isize, isize), instructions: &[Instruction]) -> (isize, isize) {
+        for instruction in instructions {
+            if let Instruction::Add { offset, .. }
+            | Instruction::Input { offset }
+            | Instruction::Output { offset }
+            | Instruction::SetZero { offset } = *instruction
+            {
+                range = (range.0.min(offset), range.1.max(offset));
+            }
+        }
+        range
+    }
+
+    fn flush(&mut self, instructions: &mut Vec<Instruction>, target: Target) {
+        // Unless the pointer wraps, moving out of bounds is an error even if
+        // no cell is accessed there, so the block must still reach its
+        // furthest positions.
+        let reached = Self::reach((self.offset.min(0), self.offset.max(0)), &self.rewritten);
+        if target.pointer_wraps || (!self.reordered && reached == (self.lowest, self.highest)) {
+            instructions.append(&mut self.rewritten);
+            if self.offset != 0 {
+                instructions.push(Instruction::Move(self.offset));
+            }
+        } else {
+            instructions.append(&mut self.original);
+        }
+        *self = Block::default();
+    }
+}
+
+/// Removes loops that can never run and clears of cells that are already
+/// zero, tracking what is known about the tape from the start of the program.
+/// Nothing is known at the start unless the tape is zeroed.
+pub(crate) fn eliminate_dead_code(
+    instructions: &[Instruction],
+    target: Target,
+) -> Vec<Instruction> {
+    let mut kept = Vec::with_capacity(instructions.len());
+    let mut facts = if target.zeroed {
+        Facts::start()
+    } else {
+        Facts::unknown()
+    };
+    let mut index = 0;
+    while index < instructions.len() {
+        let instruction = instructions[index];
+        index += 1;
+        match instruction {
+            Instruction::Add { offset, delta } => {
+                let value = facts
+                    .get(offset, target)
+                    .and_then(|value| normalize(value + i128::from(delta), target));
+                facts.set(offset, value, target);
+            }
+            Instruction::Move(delta) => facts.pointer = facts.key(delta, target),
+            Instruction::Input { offset } => facts.set(offset, None, target),
+            Instruction::Output { .. } => {}
+            Instruction::SetZero { offset } => {
+                // Clearing a cell outside the tape is still an error unless
+                // the pointer wraps.
+                let in_bounds = offset == 0 || target.pointer_wraps;
+                if in_bounds && facts.get(offset, target) == Some(0) {
+                    continue;
+                }
+                facts.set(offset, Some(0), target);
+            }
+            Instruction::MulAdd { offset, factor } => {
+                let value = match (facts.get(0, target), facts.get(offset, target)) {
+                    (Some(0), _) => continue,
+                    (Some(source), Some(value)) => source
+                        .checked_mul(factor.into())
+                        .and_then(|product| value.checked_add(product))
+                        .and_then(|value| normalize(value, target)),
+                    _ => None,
+                };
+                facts.set(offset, value, target);
+            }
+            Instruction::ScanRight(_) | Instruction::ScanLeft(_) => {
+                if facts.get(0, target) == Some(0) {
+                    continue;
+                }
+                facts = Facts::after_loop();
+            }
+            Instruction::LoopStart(end) => {
+                if facts.get(0, target) == Some(0) {
+                    index = end + 1;
+                    continue;
+                }
+                facts = Facts::unknown();
+            }
+            Instruction::LoopEnd(_) => facts = Facts::after_loop(),
+        }
+        kept.push(instruction);
+    }
+    link(&mut kept);
+    kept
+}
+
+/// Brings `value` into the range of a cell, or returns `None` if checked
+/// arithmetic would fail on it.
+fn normalize(value: i128, target: Target) -> Option<i128> {
+    match target.bits {
+        Some(bits) => {
+            let modulus = 1i128 << bits;
+            if target.wrapping {
+                Some(value.rem_euclid(modulus))
+            } else {
+                (0..modulus).contains(&value).then_some(value)
+            }
+        }
+        None => Some(value),
+    }
+}
+
+/// What is known about the tape at one point in a program. Cells are keyed by
+/// their position relative to a fixed origin, which is the first cell while
+/// the whole tape is known and an arbitrary cell otherwise.
+struct Facts {
+    /// Cells that were written, mapped to their value if it is known.
+    cells: HashMap<isize, Option<i128>>,
+    pointer: isize,
+    /// Cells missing from `cells` are still zero.
+    rest_zero: bool,
+}
+
+impl Facts {
+    fn start() -> Facts {
+        Facts {
+            cells: HashMap::new(),
+            pointer: 0,
+            rest_zero: true,
+        }
+    }
+
+    fn unknown() -> Facts {
+        Facts {
+            rest_zero: false,
+            ..Facts::start()
+        }
+    }
+
+    /// Only the current cell is known, because a loop just exited.
+    fn after_loop() -> Facts {
+        let mut facts = Facts::unknown();
+        facts.cells.insert(0, Some(0));
+        facts
+    }
+
+    fn key(&self, offset: isize, target: Target) -> isize {
+        let key = self.pointer + offset;
+        if target.pointer_wraps {
+            key.rem_euclid(target.tape_len as isize)
+        } else {
+            key
+        }
+    }
+
+    fn get(&self, offset: isize, target: Target) -> Option<i128> {
+        match self.cells.get(&self.key(offset, target)) {
+            Some(value) => *value,
+            None if self.rest_zero => Some(0),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, offset: isize, value: Option<i128>, target: Target) {
+        self.cells.insert(self.key(offset, target), value);
+    }
+}
+
+/// Recomputes the jump targets of every `LoopStart`/`LoopEnd` pair.
+pub(crate) fn link(instructions: &mut [Instruction]) {
+    let mut left = Vec::new();
+    for index in 0..instructions.len() {
+        match instructions[index] {
+            Instruction::LoopStart(_) => left.push(index),
+            Instruction::LoopEnd(_) => {
+                let start = left.pop().expect("unbalanced loop");
+                instructions[start] = Instruction::LoopStart(index);
+                instructions[index] = Instruction::LoopEnd(start);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parse;
+    use super::*;
+    use Instruction::*;
+
+    const WRAPPING: Target = Target {
+        wrapping: true,
+        bits: Some(8),
+        pointer_wraps: true,
+        tape_len: 30000,
+        zeroed: true,
+    };
+
+    const CHECKED: Target = Target {
+        wrapping: false,
+        bits: Some(8),
+        pointer_wraps: false,
+        tape_len: 30000,
+        zeroed: true,
+    };
+
+    const UNBOUNDED: Target = Target {
+        wrapping: true,
+        bits: None,
+        pointer_wraps: true,
+        tape_len: 30000,
+        zeroed: true,
+    };
+
+    fn add(offset: isize, delta: i32) -> Instruction {
+        Add { offset, delta }
+    }
+
+    fn mul_add(offset: isize, factor: i32) -> Instruction {
+        MulAdd { offset, factor }
+    }
+
+    fn fold_code(code: &str, target: Target) -> Vec<Instruction> {
+        fold(&parse(code).unwrap(), target)
+    }
+
+    fn idioms_code(code: &str, target: Target) -> Vec<Instruction> {
+        idioms(&fold_code(code, target), target)
+    }
+
+    fn offsets_code(code: &str, target: Target) -> Vec<Instruction> {
+        offsets(&idioms_code(code, target), target)
+    }
+
+    #[test]
+    fn test_fold_runs() {
+        assert_eq!(
+            fold_code("+++>>--<.", WRAPPING),
+            vec![
+                add(0, 3),
+                Move(2),
+                add(0, -2),
+                Move(-1),
+                Output { offset: 0 }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_cancel() {
+        assert_eq!(fold_code("+-><,", WRAPPING), vec![Input { offset: 0 }]);
+        assert_eq!(fold_code("+-+", WRAPPING), vec![add(0, 1)]);
+        assert_eq!(
+            fold_code("+-><,", CHECKED),
+            vec![
+                add(0, 1),
+                add(0, -1),
+                Move(1),
+                Move(-1),
+                Input { offset: 0 }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_relinks_loops() {
+        assert_eq!(
+            fold_code("++[>++[-]<-]", WRAPPING),
+            vec![
+                add(0, 2),
+                LoopStart(9),
+                Move(1),
+                add(0, 2),
+                LoopStart(6),
+                add(0, -1),
+                LoopEnd(4),
+                Move(-1),
+                add(0, -1),
+                LoopEnd(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clear_loops() {
+        assert_eq!(
+            idioms_code(">[-]<", WRAPPING),
+            vec![Move(1), SetZero { offset: 0 }, Move(-1)]
+        );
+        assert_eq!(idioms_code("[+]", WRAPPING), vec![SetZero { offset: 0 }]);
+        assert_eq!(
+            idioms_code("[+]", CHECKED),
+            vec![LoopStart(2), add(0, 1), LoopEnd(0)]
+        );
+        assert_eq!(
+            idioms_code("[-]", UNBOUNDED),
+            vec![LoopStart(2), add(0, -1), LoopEnd(0)]
+        );
+    }
+
+    #[test]
+    fn test_multiply_loops() {
+        assert_eq!(
+            idioms_code("[->+>++<<]", WRAPPING),
+            vec![mul_add(1, 1), mul_add(2, 2), SetZero { offset: 0 }]
+        );
+        assert_eq!(
+            idioms_code("[<--->+]", WRAPPING),
+            vec![mul_add(-1, 3), SetZero { offset: 0 }]
+        );
+        // Unbalanced, or decrementing by two.
+        assert_eq!(idioms_code("[->+]", WRAPPING).len(), 5);
+        assert_eq!(idioms_code("[-->+<]", WRAPPING).len(), 6);
+        // The target would go up and down within one iteration.
+        assert_eq!(idioms_code("[->+<>-<]", CHECKED).len(), 9);
+        // The pointer goes past the target, which only matters if that can
+        // fail.
+        assert_eq!(idioms_code("[->>><+<<]", CHECKED).len(), 7);
+        assert_eq!(idioms_code("[->>><+<<]", WRAPPING).len(), 2);
+    }
+
+    #[test]
+    fn test_scan_loops() {
+        assert_eq!(idioms_code("[>]", WRAPPING), vec![ScanRight(1)]);
+        assert_eq!(idioms_code("[<<<]", WRAPPING), vec![ScanLeft(3)]);
+        assert_eq!(
+            idioms_code("[>[<]]", WRAPPING),
+            vec![LoopStart(3), Move(1), ScanLeft(1), LoopEnd(0)]
+        );
+    }
+
+    #[test]
+    fn test_offsets() {
+        assert_eq!(
+            offsets_code(">+>++<<-", WRAPPING),
+            vec![add(1, 1), add(2, 2), add(0, -1)]
+        );
+        assert_eq!(
+            offsets_code(">.>,[-]<<[>>>.<<+>-]", WRAPPING),
+            vec![
+                Output { offset: 1 },
+                Input { offset: 2 },
+                SetZero { offset: 2 },
+                LoopStart(8),
+                Output { offset: 3 },
+                add(1, 1),
+                add(2, -1),
+                Move(2),
+                LoopEnd(3),
+            ]
+        );
+        assert_eq!(
+            offsets_code(">>[-<<+>>]<<.", WRAPPING),
+            vec![
+                Move(2),
+                mul_add(-2, 1),
+                SetZero { offset: 0 },
+                Output { offset: -2 },
+                Move(-2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_offsets_keep_bounds_checks() {
+        assert_eq!(offsets_code(">+<", CHECKED), vec![add(1, 1)]);
+        // The pointer reaches further than any accessed cell.
+        assert_eq!(
+            offsets_code(">>><+<", CHECKED),
+            vec![Move(3), Move(-1), add(0, 1), Move(-1)]
+        );
+        assert_eq!(offsets_code(">>><+<", WRAPPING), vec![add(2, 1), Move(1)]);
+        // Output may only move ahead of a move that can fail if a cell at
+        // least as far away was accessed first.
+        assert_eq!(
+            offsets_code(">>>+<<<.", CHECKED),
+            vec![add(3, 1), Output { offset: 0 }]
+        );
+        assert_eq!(offsets_code("+>>><<<.>>>+", CHECKED).len(), 6);
+        assert_eq!(offsets_code("+>>><<<.>>>+", WRAPPING).len(), 4);
+    }
+
+    fn optimize_code(code: &str, target: Target) -> Vec<Instruction> {
+        optimize(&parse(code).unwrap(), target)
+    }
+
+    #[test]
+    fn test_dead_comment_loop() {
+        assert_eq!(
+            optimize_code("[This is a comment, really.]+.", WRAPPING),
+            vec![add(0, 1), Output { offset: 0 }]
+        );
+        assert_eq!(optimize_code(">[-]<[>]", WRAPPING), vec![]);
+    }
+
+    #[test]
+    fn test_dead_loop_after_loop() {
+        assert_eq!(
+            optimize_code(",[.,][never]>[-]", WRAPPING),
+            vec![
+                Input { offset: 0 },
+                LoopStart(4),
+                Output { offset: 0 },
+                Input { offset: 0 },
+                LoopEnd(1),
+                SetZero { offset: 1 },
+                Move(1),
+            ]
+        );
+        assert_eq!(
+            optimize_code("+[[-]>[-]<[-]]", WRAPPING),
+            vec![
+                add(0, 1),
+                LoopStart(4),
+                SetZero { offset: 0 },
+                SetZero { offset: 1 },
+                LoopEnd(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_known_constants() {
+        // The cell wraps back to zero.
+        assert_eq!(optimize_code("-+[.]", WRAPPING), vec![]);
+        assert_eq!(optimize_code(&"+".repeat(256), WRAPPING), vec![add(0, 256)]);
+        assert_eq!(
+            optimize_code(&format!("{}[.]", "+".repeat(256)), WRAPPING),
+            vec![add(0, 256)]
+        );
+        // Two copies of 3 leave the source at zero and the target at 6.
+        assert_eq!(
+            optimize_code("+++[->++<][.]>[-]", WRAPPING),
+            vec![
+                add(0, 3),
+                mul_add(1, 2),
+                SetZero { offset: 0 },
+                SetZero { offset: 1 },
+                Move(1),
+            ]
+        );
+        assert_eq!(optimize_code(",[->+<]>[-]<[-]", WRAPPING).len(), 4);
+    }
+
+    #[test]
+    fn test_unknown_start() {
+        let target = Target {
+            zeroed: false,
+            ..WRAPPING
+        };
+        assert_eq!(optimize_code("[-]+[.]", target).len(), 5);
+        assert_eq!(optimize_code("[-][-]", target), vec![SetZero { offset: 0 }]);
+    }
+
+    #[test]
+    fn test_wrapped_pointer_aliases() {
+        let target = Target {
+            tape_len: 3,
+            ..WRAPPING
+        };
+        assert_eq!(optimize_code(">>>+<<<[.]", target).len(), 4);
+        assert_eq!(optimize_code(">>+<<[.]", target).len(), 1);
+        assert_eq!(idioms_code("[->>>+<<<]", target).len(), 6);
+        assert_eq!(idioms_code("[->>+<<]", target).len(), 2);
+    }
+
+    #[test]
+    fn test_checked_overflow_is_unknown() {
+        assert_eq!(optimize_code("-[.]", CHECKED).len(), 4);
+        assert_eq!(
+            optimize_code(">[-]", CHECKED),
+            vec![SetZero { offset: 1 }, Move(1)]
+        );
+        // Out-of-bounds clears must stay to report the error.
+        assert_eq!(
+            optimize_code("[-]>>[-]<<", CHECKED),
+            vec![SetZero { offset: 2 }]
+        );
+    }
+}
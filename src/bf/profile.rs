@@ -0,0 +1,174 @@
+//! Counting where a program spends its steps.
+
+use std::io::{Read, Write};
+
+use super::{Cell, Instruction, RuntimeErrorKind, VirtualMachine};
+
+/// Execution counts collected by a machine built with
+/// [`VmBuilder::profile`](super::VmBuilder::profile).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Profile {
+    /// Number of steps spent on each instruction, by index: one each time it
+    /// ran, plus one for each move of a scan.
+    pub counts: Vec<u64>,
+    /// Number of times the body of each loop started, by the index of its
+    /// [`Instruction::LoopStart`], and zero for other instructions.
+    pub loop_iterations: Vec<u64>,
+    /// Highest cell index the pointer reached after an instruction.
+    pub max_pointer: usize,
+    offsets: Vec<usize>,
+}
+
+impl Profile {
+    fn new(len: usize, offsets: &[usize]) -> Profile {
+        Profile {
+            counts: vec![0; len],
+            loop_iterations: vec![0; len],
+            max_pointer: 0,
+            offsets: offsets.to_vec(),
+        }
+    }
+
+    /// Number of instructions executed, the sum of [`counts`](Self::counts).
+    pub fn steps(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Number of times the instruction at each byte offset in the source ran,
+    /// in source order. Empty if the program was optimized, since optimized
+    /// instructions no longer know their source positions.
+    pub fn positions(&self) -> Vec<(usize, u64)> {
+        self.offsets
+            .iter()
+            .copied()
+            .zip(self.counts.iter().copied())
+            .collect()
+    }
+
+    /// Records that `instruction` at `index` took `steps` steps and moved on
+    /// to `next` with the pointer at `pointer`.
+    fn record(
+        &mut self,
+        index: usize,
+        instruction: Instruction,
+        steps: u64,
+        next: usize,
+        pointer: usize,
+    ) {
+        self.counts[index] += steps;
+        self.max_pointer = self.max_pointer.max(pointer);
+        match instruction {
+            Instruction::LoopStart(_) if next == index + 1 => self.loop_iterations[index] += 1,
+            Instruction::LoopEnd(start) if next == start + 1 => self.loop_iterations[start] += 1,
+            _ => {}
+        }
+    }
+}
+
+impl<R: Read, W: Write, C: Cell> VirtualMachine<R, W, C> {
+    /// Counts for the program so far, if profiling is enabled. They start
+    /// over with each [`compile`](Self::compile) and [`run`](Self::run).
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Starts new counts for the current program, if profiling is enabled.
+    pub(super) fn reset_profile(&mut self) {
+        self.profile = self
+            .config
+            .profile
+            .then(|| Profile::new(self.instructions.len(), &self.offsets));
+    }
+
+    /// Executes the instruction at the current index like
+    /// [`apply`](Self::apply), counting it in the profile.
+    pub(super) fn apply_profiled(
+        &mut self,
+        instruction: Instruction,
+        room: &mut u64,
+    ) -> Result<usize, RuntimeErrorKind> {
+        let available = *room;
+        let next = self.apply(instruction, room)?;
+        if let Some(profile) = &mut self.profile {
+            let steps = 1 + (available - *room);
+            profile.record(self.index, instruction, steps, next, self.pointer);
+        }
+        Ok(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use std::io;
+
+    fn profile(builder: VmBuilder, code: &str) -> Profile {
+        let mut vm = builder.profile(true).build(io::empty(), io::sink());
+        vm.compile(code).unwrap();
+        vm.run().unwrap();
+        vm.profile().unwrap().clone()
+    }
+
+    #[test]
+    fn test_counts() {
+        let profile = profile(VmBuilder::new().optimize(false), "++[>+++[>+<-]<-]");
+        // Each of the two outer iterations runs the inner loop three times.
+        assert_eq!(profile.steps(), 2 + 1 + 2 * (5 + 3 * 5 + 3));
+        assert_eq!(profile.counts[..3], [1, 1, 1]);
+        assert_eq!((profile.counts[7], profile.counts[8]), (2, 6));
+        assert_eq!(profile.loop_iterations[2], 2);
+        assert_eq!(profile.loop_iterations[7], 6);
+        assert_eq!(profile.loop_iterations[0], 0);
+        assert_eq!(profile.max_pointer, 2);
+        let positions = profile.positions();
+        assert_eq!(positions.len(), 16);
+        assert_eq!(positions[8], (8, 6));
+    }
+
+    #[test]
+    fn test_optimized() {
+        let profile = profile(VmBuilder::new(), "+++[>++<-]>.");
+        // The loop becomes a multiplication and a clear.
+        assert_eq!(profile.steps(), 5);
+        assert_eq!(profile.max_pointer, 1);
+        assert!(profile.positions().is_empty());
+    }
+
+    #[test]
+    fn test_scans() {
+        let mut vm = VmBuilder::new()
+            .profile(true)
+            .build(io::empty(), io::sink());
+        vm.compile("+>+>+<<[>]").unwrap();
+        let stats = vm.run().unwrap();
+        let profile = vm.profile().unwrap();
+        // The scan counts the three moves it makes.
+        assert_eq!(profile.steps(), stats.steps);
+        let scan = vm
+            .instructions()
+            .iter()
+            .position(|&instruction| instruction == Instruction::ScanRight(1))
+            .unwrap();
+        assert_eq!(profile.counts[scan], 4);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut vm = VirtualMachine::new(io::empty(), io::sink());
+        vm.compile("+").unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.profile(), None);
+    }
+
+    #[test]
+    fn test_runs_start_over() {
+        let mut vm = VmBuilder::new()
+            .profile(true)
+            .build(io::empty(), io::sink());
+        vm.compile("+[-]").unwrap();
+        vm.run().unwrap();
+        let first = vm.profile().unwrap().clone();
+        vm.run().unwrap();
+        assert_eq!(vm.profile(), Some(&first));
+    }
+}
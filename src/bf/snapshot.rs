@@ -0,0 +1,428 @@
+//! Saving and restoring the state of a running program.
+//!
+//! The binary format starts with the magic bytes `BFVM` and a version, then
+//! the cell width and a fingerprint of the compiled program, then the counters
+//! and the tape. Numbers are LEB128 varints except for the fingerprint, and
+//! the tape is stored as alternating runs of zero cells and encoded cells.
+
+use std::error;
+use std::fmt;
+use std::io::{Read, Write};
+
+use super::{Cell, ExecutionStats, Instruction, PointerMode, VirtualMachine};
+
+const MAGIC: &[u8; 4] = b"BFVM";
+const VERSION: u8 = 1;
+/// Longest tape [`VmState::from_bytes`] accepts, so that a corrupt length
+/// cannot exhaust memory.
+const MAX_TAPE_LEN: usize = 1 << 28;
+
+/// Everything needed to continue a program where it stopped, as taken by
+/// [`VirtualMachine::snapshot`].
+///
+/// The input and output streams are not part of the state. When restoring
+/// after a restart, the input should continue after the
+/// [`bytes_read`](ExecutionStats::bytes_read) bytes that were already read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VmState<C> {
+    /// Index of the next instruction to execute.
+    pub index: usize,
+    pub pointer: usize,
+    pub tape: Vec<C>,
+    pub stats: ExecutionStats,
+    /// Fingerprint of the compiled program, which has to match on restore.
+    program: u64,
+}
+
+/// Why a [`VmState`] could not be decoded or restored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidState {
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid machine state: {}", self.reason)
+    }
+}
+
+impl error::Error for InvalidState {}
+
+fn invalid(reason: &'static str) -> InvalidState {
+    InvalidState { reason }
+}
+
+impl<C: Cell> VmState<C> {
+    /// Encodes the state in a compact binary format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(MAGIC);
+        out.push(VERSION);
+        write_varint(&mut out, C::BITS.unwrap_or(0).into());
+        out.extend(self.program.to_le_bytes());
+        for value in [
+            self.index as u64,
+            self.pointer as u64,
+            self.stats.steps,
+            self.stats.bytes_read,
+            self.stats.bytes_written,
+            self.tape.len() as u64,
+        ] {
+            write_varint(&mut out, value);
+        }
+        let mut rest = &self.tape[..];
+        while !rest.is_empty() {
+            let zeros = rest.iter().take_while(|cell| cell.is_zero()).count();
+            let cells = rest[zeros..]
+                .iter()
+                .take_while(|cell| !cell.is_zero())
+                .count();
+            write_varint(&mut out, zeros as u64);
+            write_varint(&mut out, cells as u64);
+            for cell in &rest[zeros..zeros + cells] {
+                cell.encode(&mut out);
+            }
+            rest = &rest[zeros + cells..];
+        }
+        out
+    }
+
+    /// Decodes a state encoded by [`to_bytes`](Self::to_bytes) for the same
+    /// cell type. Tapes of more than 2<sup>28</sup> cells are rejected.
+    pub fn from_bytes(bytes: &[u8]) -> Result<VmState<C>, InvalidState> {
+        let header = bytes.get(..MAGIC.len() + 1);
+        if header.map(|header| &header[..MAGIC.len()]) != Some(MAGIC) {
+            return Err(invalid("not a machine state"));
+        }
+        if header.map(|header| header[MAGIC.len()]) != Some(VERSION) {
+            return Err(invalid("unsupported version"));
+        }
+        let mut reader = Reader {
+            bytes,
+            at: MAGIC.len() + 1,
+        };
+        if reader.varint()? != u64::from(C::BITS.unwrap_or(0)) {
+            return Err(invalid("different cell width"));
+        }
+        let program = reader.take(8)?.try_into().map(u64::from_le_bytes).unwrap();
+        let index = reader.usize()?;
+        let pointer = reader.usize()?;
+        let stats = ExecutionStats {
+            steps: reader.varint()?,
+            bytes_read: reader.varint()?,
+            bytes_written: reader.varint()?,
+        };
+        let len = reader.usize()?;
+        if len > MAX_TAPE_LEN {
+            return Err(invalid("tape too long"));
+        }
+        let mut tape = Vec::new();
+        tape.try_reserve_exact(len)
+            .map_err(|_| invalid("tape too long"))?;
+        while tape.len() < len {
+            let zeros = reader.usize()?;
+            let cells = reader.usize()?;
+            let end = tape.len().checked_add(zeros).filter(|&end| end <= len);
+            tape.resize(end.ok_or(invalid("tape too long"))?, C::default());
+            for _ in 0..cells {
+                if tape.len() == len {
+                    return Err(invalid("tape too long"));
+                }
+                let (cell, size) = C::decode(&bytes[reader.at..]).ok_or(invalid("bad cell"))?;
+                reader.at += size;
+                tape.push(cell);
+            }
+        }
+        if reader.at != bytes.len() {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(VmState {
+            index,
+            pointer,
+            tape,
+            stats,
+            program,
+        })
+    }
+}
+
+impl<R: Read, W: Write, C: Cell> VirtualMachine<R, W, C> {
+    /// Captures the state of the program, to be continued later with
+    /// [`restore`](Self::restore).
+    pub fn snapshot(&self) -> VmState<C> {
+        VmState {
+            index: self.index,
+            pointer: self.pointer,
+            tape: self.memory.clone(),
+            stats: self.stats,
+            program: fingerprint(&self.instructions),
+        }
+    }
+
+    /// Puts the machine back into `state`, which must have been taken from a
+    /// machine that compiled the same code with the same configuration.
+    /// [`resume`](Self::resume) or
+    /// [`run_until_blocked`](Self::run_until_blocked) then continue exactly as
+    /// the original machine would have.
+    pub fn restore(&mut self, state: VmState<C>) -> Result<(), InvalidState> {
+        if state.program != fingerprint(&self.instructions) {
+            return Err(invalid("different program"));
+        }
+        if state.index > self.instructions.len() {
+            return Err(invalid("instruction index out of range"));
+        }
+        // Only an extending tape can have grown past the configured length.
+        let len = state.tape.len();
+        let tape_len = self.config.tape_len;
+        if len < tape_len || (len > tape_len && self.config.pointer_mode != PointerMode::Extend) {
+            return Err(invalid("different tape length"));
+        }
+        if state.pointer >= state.tape.len() {
+            return Err(invalid("pointer out of range"));
+        }
+        self.index = state.index;
+        self.pointer = state.pointer;
+        self.memory = state.tape;
+        self.stats = state.stats;
+        Ok(())
+    }
+}
+
+/// FNV-1a hash of the compiled instructions in the encoding of
+/// [`encode_instruction`], which stays the same across builds.
+fn fingerprint(instructions: &[Instruction]) -> u64 {
+    let mut bytes = vec![VERSION];
+    for &instruction in instructions {
+        encode_instruction(&mut bytes, instruction);
+    }
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Appends a tag for the kind of `instruction` and its operands as varints,
+/// zigzag-encoded if they are signed. Changing this invalidates saved states,
+/// and needs a new [`VERSION`].
+fn encode_instruction(out: &mut Vec<u8>, instruction: Instruction) {
+    let signed = |value: i64| ((value << 1) ^ (value >> 63)) as u64;
+    let (tag, operands) = match instruction {
+        Instruction::Add { offset, delta } => (0, [signed(offset as i64), signed(delta.into())]),
+        Instruction::Move(delta) => (1, [signed(delta as i64), 0]),
+        Instruction::Input { offset } => (2, [signed(offset as i64), 0]),
+        Instruction::Output { offset } => (3, [signed(offset as i64), 0]),
+        Instruction::LoopStart(end) => (4, [end as u64, 0]),
+        Instruction::LoopEnd(start) => (5, [start as u64, 0]),
+        Instruction::SetZero { offset } => (6, [signed(offset as i64), 0]),
+        Instruction::MulAdd { offset, factor } => {
+            (7, [signed(offset as i64), signed(factor.into())])
+        }
+        Instruction::ScanRight(stride) => (8, [stride as u64, 0]),
+        Instruction::ScanLeft(stride) => (9, [stride as u64, 0]),
+    };
+    out.push(tag);
+    for operand in operands {
+        write_varint(out, operand);
+    }
+}
+
+pub(super) fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Reads a varint from the start of `bytes`, returning it with its length.
+pub(super) fn read_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (index, &byte) in bytes.iter().enumerate().take(10) {
+        let bits = u64::from(byte & 0x7F);
+        if index == 9 && bits > 1 {
+            return None;
+        }
+        value |= bits << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, index + 1));
+        }
+    }
+    None
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], InvalidState> {
+        let bytes = self.bytes.get(self.at..self.at + len);
+        self.at += len;
+        bytes.ok_or(invalid("truncated"))
+    }
+
+    fn varint(&mut self) -> Result<u64, InvalidState> {
+        let (value, len) = read_varint(&self.bytes[self.at..]).ok_or(invalid("truncated"))?;
+        self.at += len;
+        Ok(value)
+    }
+
+    fn usize(&mut self) -> Result<usize, InvalidState> {
+        usize::try_from(self.varint()?).map_err(|_| invalid("number out of range"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+    use std::io;
+
+    const PROGRAM: &str = ",[>+++++[>+++<-]<-]>>[.-]<<,.";
+
+    fn vm(input: &[u8]) -> VirtualMachine<&[u8], Vec<u8>> {
+        let mut vm = VmBuilder::new().optimize(false).build(input, Vec::new());
+        vm.compile(PROGRAM).unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_varints() {
+        for value in [0, 1, 127, 128, 300, u32::MAX.into(), u64::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            assert_eq!(read_varint(&out), Some((value, out.len())));
+        }
+        assert_eq!(read_varint(&[0x80]), None);
+        assert_eq!(read_varint(&[0xFF; 10]), None);
+    }
+
+    #[test]
+    fn test_resume_from_bytes() {
+        let mut expected = vm(b"\x03!");
+        expected.resume().unwrap();
+
+        for steps in [0, 1, 20, 150] {
+            let mut first = vm(b"\x03!");
+            for _ in 0..steps {
+                first.step().unwrap();
+            }
+            let bytes = first.snapshot().to_bytes();
+            // A new machine picks up the input after what was read.
+            let read = first.stats().bytes_read as usize;
+            let mut second = vm(&b"\x03!"[read..]);
+            second
+                .restore(VmState::from_bytes(&bytes).unwrap())
+                .unwrap();
+            second.resume().unwrap();
+
+            let mut output = first.output.clone();
+            output.extend(&second.output);
+            assert_eq!(output, expected.output, "after {} steps", steps);
+            assert_eq!(second.snapshot(), expected.snapshot());
+        }
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut vm = vm(b"\x01");
+        vm.resume().unwrap();
+        let state = vm.snapshot();
+        let bytes = state.to_bytes();
+        assert!(bytes.len() < 40, "{} bytes", bytes.len());
+        assert_eq!(VmState::from_bytes(&bytes), Ok(state));
+    }
+
+    #[test]
+    fn test_invalid_bytes() {
+        let bytes = vm(b"").snapshot().to_bytes();
+        let error = |bytes: &[u8]| VmState::<u8>::from_bytes(bytes).unwrap_err().reason;
+        assert_eq!(error(b"BFV"), "not a machine state");
+        assert_eq!(error(&bytes[..bytes.len() - 1]), "truncated");
+        assert_eq!(error(&[&bytes[..], &[0]].concat()), "trailing bytes");
+        assert_eq!(
+            VmState::<u16>::from_bytes(&bytes).unwrap_err().reason,
+            "different cell width"
+        );
+        let mut long = bytes.clone();
+        long.extend([0x90, 0x03, 0]);
+        assert_eq!(error(&long), "trailing bytes");
+
+        // A huge tape of zeros takes only a few bytes to describe.
+        let mut huge = bytes[..MAGIC.len() + 1 + 1 + 8].to_vec();
+        for value in [0, 0, 0, 0, 0, 1 << 40, 1 << 40, 0] {
+            write_varint(&mut huge, value);
+        }
+        assert_eq!(error(&huge), "tape too long");
+    }
+
+    #[test]
+    fn test_restore_checks() {
+        let state = vm(b"").snapshot();
+        let mut other = VirtualMachine::new(io::empty(), io::sink());
+        other.compile("+").unwrap();
+        let err = other.restore(state.clone()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid machine state: different program");
+        let mut same = vm(b"");
+        let bad = VmState {
+            pointer: 30000,
+            ..state
+        };
+        assert_eq!(
+            same.restore(bad.clone()).unwrap_err().reason,
+            "pointer out of range"
+        );
+        let short = VmState {
+            pointer: 0,
+            tape: vec![0; 10],
+            ..bad
+        };
+        let err = same.restore(short.clone()).unwrap_err();
+        assert_eq!(err.reason, "different tape length");
+        let long = VmState {
+            tape: vec![0; 30001],
+            ..short
+        };
+        let err = same.restore(long.clone()).unwrap_err();
+        assert_eq!(err.reason, "different tape length");
+
+        // Only an extending tape may have grown.
+        let mut extend = VmBuilder::new()
+            .optimize(false)
+            .pointer_mode(PointerMode::Extend)
+            .build(&b""[..], Vec::new());
+        extend.compile(PROGRAM).unwrap();
+        extend.restore(long).unwrap();
+        assert_eq!(extend.memory().len(), 30001);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        // Saved states depend on these staying the same.
+        assert_eq!(fingerprint(&[]), 0xaf63_bc4c_8601_b62c);
+        let program = parse("+[->>+<<]<,.").unwrap();
+        assert_eq!(fingerprint(&program), 0xddfa_0945_08fa_b4dc);
+        let mut bytes = Vec::new();
+        encode_instruction(
+            &mut bytes,
+            Instruction::MulAdd {
+                offset: -2,
+                factor: 300,
+            },
+        );
+        assert_eq!(bytes, [7, 3, 0xD8, 0x04]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json() {
+        let mut vm = vm(b"\x02");
+        vm.resume().unwrap();
+        let state = vm.snapshot();
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(json.contains("\"stats\":{\"steps\":"), "{}", json);
+        let decoded: VmState<u8> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, state);
+    }
+}
@@ -0,0 +1,372 @@
+//! A minimal x86-64 encoder covering the instructions the native backends
+//! emit.
+
+/// The registers the backends use, by encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsi = 6,
+    Rdi = 7,
+    R12 = 12,
+    #[cfg(any(
+        test,
+        all(feature = "jit", target_arch = "x86_64", target_os = "linux")
+    ))]
+    R13 = 13,
+    R14 = 14,
+    #[cfg(any(
+        test,
+        all(feature = "jit", target_arch = "x86_64", target_os = "linux")
+    ))]
+    R15 = 15,
+}
+
+impl Reg {
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+}
+
+/// A memory operand `[base + index + disp]`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Mem {
+    pub base: Reg,
+    pub index: Option<Reg>,
+    pub disp: i32,
+}
+
+impl Mem {
+    pub fn new(base: Reg, disp: i32) -> Mem {
+        Mem {
+            base,
+            index: None,
+            disp,
+        }
+    }
+
+    pub fn indexed(base: Reg, index: Reg, disp: i32) -> Mem {
+        Mem {
+            base,
+            index: Some(index),
+            disp,
+        }
+    }
+}
+
+/// Condition codes for [`Assembler::jcc`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Cond {
+    /// Unsigned `<`.
+    B = 0x2,
+    /// Unsigned `>=`.
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    /// Negative.
+    S = 0x8,
+    /// Signed `<=`.
+    Le = 0xE,
+}
+
+/// A jump target, bound to a position with [`Assembler::bind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Label(usize);
+
+#[derive(Debug, Default)]
+pub(crate) struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// Positions of rel32 fields and the labels they refer to.
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    /// Resolves all jumps and returns the machine code.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a jump refers to a label that was never bound.
+    pub fn finish(mut self) -> Vec<u8> {
+        for &(at, label) in &self.fixups {
+            let target = self.labels[label.0].expect("unbound label");
+            let rel = target as i64 - (at as i64 + 4);
+            self.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        self.code
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    fn rex(&mut self, wide: bool, reg: u8, index: u8, base: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | base >> 3;
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    /// Emits an instruction with a memory operand: prefix, opcode, ModRM,
+    /// optional SIB and a 32-bit displacement.
+    fn op_mem(&mut self, wide: bool, opcode: &[u8], reg: u8, mem: Mem) {
+        let index = mem.index.map_or(0, |index| index as u8);
+        self.rex(wide, reg, index, mem.base as u8);
+        self.code.extend_from_slice(opcode);
+        let reg = (reg & 7) << 3;
+        match mem.index {
+            Some(index) => {
+                self.code.push(0x84 | reg);
+                self.code.push(index.low() << 3 | mem.base.low());
+            }
+            None if mem.base.low() == 4 => {
+                self.code.push(0x84 | reg);
+                self.code.push(0x24);
+            }
+            None => self.code.push(0x80 | reg | mem.base.low()),
+        }
+        self.code.extend_from_slice(&mem.disp.to_le_bytes());
+    }
+
+    /// Emits an instruction with two register operands.
+    fn op_reg(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: Reg) {
+        self.rex(wide, reg, 0, rm as u8);
+        self.code.extend_from_slice(opcode);
+        self.code.push(0xC0 | (reg & 7) << 3 | rm.low());
+    }
+
+    pub fn syscall(&mut self) {
+        self.code.extend_from_slice(&[0x0F, 0x05]);
+    }
+
+    /// `cqo`, sign-extending rax into rdx.
+    pub fn cqo(&mut self) {
+        self.code.extend_from_slice(&[0x48, 0x99]);
+    }
+
+    /// `idiv src`, dividing rdx:rax by a 64-bit register.
+    pub fn idiv(&mut self, src: Reg) {
+        self.op_reg(true, &[0xF7], 7, src);
+    }
+
+    /// `mov dst, src` on 64-bit registers.
+    pub fn mov(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(true, &[0x89], src as u8, dst);
+    }
+
+    /// `mov dst, imm64`, or the shorter `mov dst32, imm32` if `imm` fits,
+    /// which zeroes the upper bits.
+    pub fn mov_imm(&mut self, dst: Reg, imm: u64) {
+        match u32::try_from(imm) {
+            Ok(imm) => {
+                self.rex(false, 0, 0, dst as u8);
+                self.code.push(0xB8 + dst.low());
+                self.code.extend_from_slice(&imm.to_le_bytes());
+            }
+            Err(_) => {
+                self.rex(true, 0, 0, dst as u8);
+                self.code.push(0xB8 + dst.low());
+                self.code.extend_from_slice(&imm.to_le_bytes());
+            }
+        }
+    }
+
+    /// `lea dst, [mem]`
+    pub fn lea(&mut self, dst: Reg, mem: Mem) {
+        self.op_mem(true, &[0x8D], dst as u8, mem);
+    }
+
+    /// `cmp a, b` on 64-bit registers.
+    pub fn cmp(&mut self, a: Reg, b: Reg) {
+        self.op_reg(true, &[0x39], b as u8, a);
+    }
+
+    /// `test a, b` on 32-bit registers.
+    pub fn test32(&mut self, a: Reg, b: Reg) {
+        self.op_reg(false, &[0x85], b as u8, a);
+    }
+
+    /// `imul dst, src, imm32` on 32-bit registers.
+    pub fn imul32(&mut self, dst: Reg, src: Reg, imm: i32) {
+        self.op_reg(false, &[0x69], dst as u8, src);
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// `movzx dst, byte [mem]`, zeroing the upper bits of `dst`.
+    pub fn load_byte(&mut self, dst: Reg, mem: Mem) {
+        self.op_mem(false, &[0x0F, 0xB6], dst as u8, mem);
+    }
+
+    /// `add byte [mem], imm8`
+    pub fn add_byte(&mut self, mem: Mem, imm: u8) {
+        self.op_mem(false, &[0x80], 0, mem);
+        self.code.push(imm);
+    }
+
+    /// `add byte [mem], src`, where `src` is the low byte of rax, rcx, rdx
+    /// or rbx.
+    pub fn add_byte_reg(&mut self, mem: Mem, src: Reg) {
+        self.op_mem(false, &[0x00], src as u8, mem);
+    }
+
+    /// `mov byte [mem], imm8`
+    pub fn store_byte(&mut self, mem: Mem, imm: u8) {
+        self.op_mem(false, &[0xC6], 0, mem);
+        self.code.push(imm);
+    }
+
+    /// `cmp byte [mem], imm8`
+    pub fn cmp_byte(&mut self, mem: Mem, imm: u8) {
+        self.op_mem(false, &[0x80], 7, mem);
+        self.code.push(imm);
+    }
+
+    pub fn jmp(&mut self, target: Label) {
+        self.code.push(0xE9);
+        self.rel32(target);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, target: Label) {
+        self.code.extend_from_slice(&[0x0F, 0x80 + cond as u8]);
+        self.rel32(target);
+    }
+
+    fn rel32(&mut self, target: Label) {
+        self.fixups.push((self.code.len(), target));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+}
+
+/// Instructions only the JIT needs, to save registers and call back into
+/// the machine.
+#[cfg(any(
+    test,
+    all(feature = "jit", target_arch = "x86_64", target_os = "linux")
+))]
+impl Assembler {
+    pub fn push(&mut self, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8);
+        self.code.push(0x50 + reg.low());
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8);
+        self.code.push(0x58 + reg.low());
+    }
+
+    pub fn ret(&mut self) {
+        self.code.push(0xC3);
+    }
+
+    /// `call reg`
+    pub fn call(&mut self, target: Reg) {
+        self.op_reg(false, &[0xFF], 2, target);
+    }
+
+    /// `mov dst, qword [mem]`
+    pub fn load(&mut self, dst: Reg, mem: Mem) {
+        self.op_mem(true, &[0x8B], dst as u8, mem);
+    }
+
+    /// `mov qword [mem], src`
+    pub fn store(&mut self, mem: Mem, src: Reg) {
+        self.op_mem(true, &[0x89], src as u8, mem);
+    }
+
+    /// `mov qword [mem], imm32`, sign-extending the immediate.
+    pub fn store_imm(&mut self, mem: Mem, imm: i32) {
+        self.op_mem(true, &[0xC7], 0, mem);
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(emit: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut asm = Assembler::new();
+        emit(&mut asm);
+        asm.finish()
+    }
+
+    #[test]
+    fn test_registers() {
+        assert_eq!(encode(|asm| asm.push(Reg::R12)), [0x41, 0x54]);
+        assert_eq!(encode(|asm| asm.pop(Reg::Rbx)), [0x5B]);
+        assert_eq!(encode(|asm| asm.pop(Reg::R15)), [0x41, 0x5F]);
+        assert_eq!(
+            encode(|asm| asm.mov(Reg::R13, Reg::Rdi)),
+            [0x49, 0x89, 0xFD]
+        );
+        assert_eq!(
+            encode(|asm| asm.cmp(Reg::Rax, Reg::R14)),
+            [0x4C, 0x39, 0xF0]
+        );
+        assert_eq!(encode(|asm| asm.call(Reg::Rax)), [0xFF, 0xD0]);
+        assert_eq!(encode(|asm| asm.test32(Reg::Rax, Reg::Rax)), [0x85, 0xC0]);
+        assert_eq!(encode(|asm| asm.idiv(Reg::R14)), [0x49, 0xF7, 0xFE]);
+        assert_eq!(encode(|asm| asm.syscall()), [0x0F, 0x05]);
+        assert_eq!(encode(|asm| asm.ret()), [0xC3]);
+        assert_eq!(
+            encode(|asm| asm.mov_imm(Reg::R14, 1)),
+            [0x41, 0xBE, 1, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|asm| asm.mov_imm(Reg::Rbx, 1 << 32)),
+            [0x48, 0xBB, 0, 0, 0, 0, 1, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_memory() {
+        let cell = Mem::indexed(Reg::Rbx, Reg::R12, -2);
+        assert_eq!(
+            encode(|asm| asm.add_byte(cell, 5)),
+            [0x42, 0x80, 0x84, 0x23, 0xFE, 0xFF, 0xFF, 0xFF, 0x05]
+        );
+        assert_eq!(
+            encode(|asm| asm.load(Reg::R12, Mem::new(Reg::R13, 16))),
+            [0x4D, 0x8B, 0xA5, 0x10, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|asm| asm.store(Mem::new(Reg::R13, 16), Reg::R12)),
+            [0x4D, 0x89, 0xA5, 0x10, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|asm| asm.store_imm(Mem::new(Reg::Rbx, 8), -1)),
+            [0x48, 0xC7, 0x83, 0x08, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            encode(|asm| asm.lea(Reg::Rax, Mem::new(Reg::R12, 1))),
+            [0x49, 0x8D, 0x84, 0x24, 0x01, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let code = encode(|asm| {
+            let top = asm.label();
+            let end = asm.label();
+            asm.bind(top);
+            asm.jcc(Cond::E, end);
+            asm.jmp(top);
+            asm.bind(end);
+        });
+        assert_eq!(
+            code,
+            [0x0F, 0x84, 0x05, 0, 0, 0, 0xE9, 0xF5, 0xFF, 0xFF, 0xFF]
+        );
+    }
+}
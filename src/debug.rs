@@ -0,0 +1,216 @@
+//! The debugger started by `bf debug`.
+
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::process::ExitCode;
+
+use bf::bf::{Breakpoint, Cell, Stop, VirtualMachine, VmBuilder};
+
+use crate::repl::tape_window;
+use crate::{fail, open_input, Options, EXIT_COMPILE, EXIT_RUNTIME, EXIT_USAGE};
+
+const HELP: &str = "\
+  step [N], s [N]     Run the next N instructions, one by default
+  next, n             Run the next instruction, or all of the loop it starts
+  continue, c         Run until a breakpoint or the end of the program,
+                      showing the tape at each `#` on the way
+  break L:C, b L:C    Stop before the command at line L, column C
+  watch CELL, w CELL  Stop after the value of cell CELL changes
+  delete, d           Remove all breakpoints and watchpoints
+  tape, t             Show the cells around the pointer
+  help, h             Show this help
+  quit, q             Leave the debugger
+
+An empty line repeats the last command.
+";
+
+pub fn run<C: Cell>(builder: VmBuilder<C>, options: &Options) -> ExitCode {
+    let source = options.source.as_ref().expect("debug needs a program");
+    let (name, code) = match source.read() {
+        Ok(program) => program,
+        Err(err) => return fail(err, EXIT_USAGE),
+    };
+    let input = match open_input(options) {
+        Ok(input) => input,
+        Err(err) => return fail(err, EXIT_USAGE),
+    };
+    // Source positions are only kept for unoptimized programs.
+    let mut vm = builder.optimize(false).build(input, io::stdout());
+    if let Err(err) = vm.compile(&code) {
+        return fail(format!("{}: {}", name, err), EXIT_COMPILE);
+    }
+    let mut debugger = Debugger {
+        vm,
+        code,
+        breakpoints: Vec::new(),
+    };
+    debugger.show();
+
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut last = String::new();
+    loop {
+        if interactive {
+            print!("(bf) ");
+            let _ = io::stdout().flush();
+        }
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(err) => return fail(err, EXIT_RUNTIME),
+        }
+        let command = match line.trim() {
+            "" => last.clone(),
+            command => command.to_string(),
+        };
+        if !debugger.command(&command) {
+            break;
+        }
+        last = command;
+    }
+    ExitCode::SUCCESS
+}
+
+struct Debugger<R: Read, W: Write, C: Cell> {
+    vm: VirtualMachine<R, W, C>,
+    code: String,
+    breakpoints: Vec<Breakpoint>,
+}
+
+impl<R: Read, W: Write, C: Cell> Debugger<R, W, C> {
+    /// Runs a command, returning whether the session goes on.
+    fn command(&mut self, command: &str) -> bool {
+        let mut words = command.split_whitespace();
+        let (name, argument) = (words.next().unwrap_or(""), words.next());
+        if words.next().is_some() {
+            eprintln!("error: too many arguments, see help");
+            return true;
+        }
+        match (name, argument) {
+            ("step" | "s", count) => {
+                let count = match count.map(str::parse).unwrap_or(Ok(1)) {
+                    Ok(count) => count,
+                    Err(_) => {
+                        eprintln!("error: invalid step count");
+                        return true;
+                    }
+                };
+                for _ in 0..count {
+                    match self.vm.step() {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(err) => {
+                            eprintln!("error: {}", err);
+                            break;
+                        }
+                    }
+                }
+                self.show();
+            }
+            ("next" | "n", None) => {
+                if let Err(err) = self.vm.step_over_loop() {
+                    eprintln!("error: {}", err);
+                }
+                self.show();
+            }
+            ("continue" | "c", None) => self.resume(),
+            ("break" | "b", Some(position)) => match self.parse_position(position) {
+                Some(offset) => self.breakpoints.push(Breakpoint::Source(offset)),
+                None => eprintln!("error: no line and column '{}' in the program", position),
+            },
+            ("watch" | "w", Some(cell)) => match cell.parse() {
+                Ok(cell) => self.breakpoints.push(Breakpoint::Cell(cell)),
+                Err(_) => eprintln!("error: invalid cell '{}'", cell),
+            },
+            ("delete" | "d", None) => self.breakpoints.clear(),
+            ("tape" | "t", None) => print!("{}", tape_window(self.vm.memory(), self.vm.pointer())),
+            ("help" | "h", None) => print!("{}", HELP),
+            ("quit" | "q", None) => return false,
+            _ => eprintln!("error: unknown command '{}', see help", command),
+        }
+        true
+    }
+
+    /// Continues to the next breakpoint, dumping the tape at each `#`.
+    fn resume(&mut self) {
+        loop {
+            match self.vm.continue_until(&self.breakpoints) {
+                Ok(Stop::Dump) => {
+                    let _ = io::stdout().flush();
+                    print!("{}", tape_window(self.vm.memory(), self.vm.pointer()));
+                    continue;
+                }
+                Ok(Stop::Breakpoint(Breakpoint::Cell(cell))) => {
+                    let _ = io::stdout().flush();
+                    println!("cell {} changed", cell);
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("error: {}", err),
+            }
+            break;
+        }
+        self.show();
+    }
+
+    /// Shows the next instruction in its line of source, and the tape.
+    fn show(&self) {
+        let _ = io::stdout().flush();
+        let Some(offset) = self.vm.source_offset(self.vm.index()) else {
+            println!("program ended");
+            return;
+        };
+        let (line, column) = position(&self.code, offset);
+        let text = self.code.split('\n').nth(line - 1).unwrap_or("");
+        println!("line {}, column {}:", line, column);
+        println!("  {}", text.trim_end());
+        println!("  {:>1$}", "^", column);
+        print!("{}", tape_window(self.vm.memory(), self.vm.pointer()));
+    }
+
+    /// Parses `LINE:COLUMN` into a byte offset in the source.
+    fn parse_position(&self, position: &str) -> Option<usize> {
+        let (line, column) = position.split_once(':')?;
+        offset(&self.code, line.parse().ok()?, column.parse().ok()?)
+    }
+}
+
+/// Line and column of the byte `offset` in `code`, counted from 1 like those
+/// of a `CompileError`.
+pub fn position(code: &str, offset: usize) -> (usize, usize) {
+    let before = &code[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
+}
+
+/// Byte offset of `line` and `column` in `code`, if it has them.
+fn offset(code: &str, line: usize, column: usize) -> Option<usize> {
+    let mut start = 0;
+    for (index, text) in code.split('\n').enumerate() {
+        if index + 1 == line {
+            let (at, _) = text.char_indices().nth(column.checked_sub(1)?)?;
+            return Some(start + at);
+        }
+        start += text.len() + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_positions() {
+        let code = "+\n>é.\n";
+        assert_eq!(position(code, 0), (1, 1));
+        assert_eq!(position(code, 3), (2, 2));
+        assert_eq!(position(code, 5), (2, 3));
+        assert_eq!(offset(code, 2, 3), Some(5));
+        assert_eq!(offset(code, 1, 1), Some(0));
+        assert_eq!(offset(code, 1, 2), None);
+        assert_eq!(offset(code, 0, 1), None);
+        assert_eq!(offset(code, 2, 0), None);
+        assert_eq!(offset(code, 4, 1), None);
+    }
+}
@@ -0,0 +1,394 @@
+//! The `bf` command-line interpreter.
+
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use bf::bf::{Arithmetic, Backend, Cell, EofPolicy, PointerMode, VmBuilder, MEMORY_SIZE};
+
+mod debug;
+mod profile;
+mod repl;
+
+const USAGE: &str = "\
+Usage: bf run [OPTIONS] FILE
+       bf -e CODE [OPTIONS]
+       bf repl [OPTIONS] [FILE]
+       bf debug [OPTIONS] FILE
+       bf profile [OPTIONS] FILE
+
+Runs a Brainfuck program, reading its input from standard input. `repl`
+starts an interactive session that keeps the tape from one line to the next,
+after running FILE if given, and `debug` steps through a program. `profile`
+runs a program and then prints how often each command ran to standard error.
+
+Options:
+  -e, --eval CODE         Run CODE instead of a file
+  -i, --input FILE        Read the program's input from FILE
+  -t, --tape-size CELLS   Number of cells on the tape [default: 30000]
+  -c, --cell BITS         Cell width: 8, 16, 32 or 64, or big for unbounded
+                          cells if built with bignum [default: 8]
+      --eof POLICY        What ',' stores at the end of input: unchanged,
+                          zero, minus-one or error [default: unchanged]
+      --pointer MODE      What happens when the pointer leaves the tape:
+                          wrap, error or extend [default: wrap]
+      --arithmetic MODE   What happens when a cell overflows: wrapping or
+                          checked [default: wrapping]
+      --no-optimize       Run the program as written
+      --partial-eval STEPS
+                          Run up to STEPS steps of the program's start at
+                          compile time
+      --backend NAME      interpreter, jit or cranelift, if built with them
+                          [default: interpreter]
+      --step-limit STEPS  Stop with an error after running STEPS instructions
+  -h, --help              Print this help
+
+Exit status: 0 on success, 1 on a runtime error, 2 on a usage error or an
+unreadable file, 3 on a compile error.
+";
+
+const EXIT_RUNTIME: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_COMPILE: u8 = 3;
+
+/// Where the program comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    File(PathBuf),
+    Inline(String),
+}
+
+impl Source {
+    /// Reads the program, returning a name for it in messages and its code.
+    fn read(&self) -> Result<(String, String), String> {
+        match self {
+            Source::Inline(code) => Ok(("-e".into(), code.clone())),
+            Source::File(path) => fs::read_to_string(path)
+                .map(|code| (path.display().to_string(), code))
+                .map_err(|err| format!("{}: {}", path.display(), err)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Width {
+    U8,
+    U16,
+    U32,
+    U64,
+    #[cfg(feature = "bignum")]
+    Big,
+}
+
+/// Options for running a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Options {
+    source: Option<Source>,
+    input: Option<PathBuf>,
+    tape_len: usize,
+    width: Width,
+    eof_policy: EofPolicy,
+    pointer_mode: PointerMode,
+    arithmetic: Arithmetic,
+    optimize: bool,
+    partial_eval: Option<u64>,
+    backend: Backend,
+    step_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    Run(Options),
+    Repl(Options),
+    Debug(Options),
+    Profile(Options),
+    Help,
+}
+
+/// Calls `$f(builder, options)` with a builder for the configured cell type.
+macro_rules! with_cell {
+    ($f:path, $options:expr) => {{
+        let options = $options;
+        let builder = VmBuilder::new()
+            .tape_len(options.tape_len)
+            .eof_policy(options.eof_policy)
+            .pointer_mode(options.pointer_mode)
+            .arithmetic(options.arithmetic)
+            .optimize(options.optimize)
+            .partial_eval(options.partial_eval)
+            .backend(options.backend)
+            .step_limit(options.step_limit);
+        match options.width {
+            Width::U8 => $f(builder, options),
+            Width::U16 => $f(builder.cell::<u16>(), options),
+            Width::U32 => $f(builder.cell::<u32>(), options),
+            Width::U64 => $f(builder.cell::<u64>(), options),
+            #[cfg(feature = "bignum")]
+            Width::Big => $f(builder.cell::<num_bigint::BigInt>(), options),
+        }
+    }};
+}
+
+fn main() -> ExitCode {
+    let command = match parse_args(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("bf: {}\nTry 'bf --help' for more information.", err);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    match command {
+        Command::Help => {
+            print!("{}", USAGE);
+            ExitCode::SUCCESS
+        }
+        Command::Run(options) => with_cell!(run, &options),
+        Command::Repl(options) => with_cell!(repl::run, &options),
+        Command::Debug(options) => with_cell!(debug::run, &options),
+        Command::Profile(options) => with_cell!(profile::run, &options),
+    }
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.into_iter().peekable();
+    let command = match args.peek().map(String::as_str) {
+        None => return Err("no program given".into()),
+        Some("run" | "repl" | "debug" | "profile") => args.next().unwrap(),
+        Some("help" | "-h" | "--help") => return Ok(Command::Help),
+        Some(arg) if arg.starts_with('-') => "run".into(),
+        Some(arg) => return Err(format!("unknown command '{}'", arg)),
+    };
+
+    let mut options = Options {
+        source: None,
+        input: None,
+        tape_len: MEMORY_SIZE,
+        width: Width::U8,
+        eof_policy: EofPolicy::default(),
+        pointer_mode: PointerMode::default(),
+        arithmetic: Arithmetic::default(),
+        optimize: true,
+        partial_eval: None,
+        backend: Backend::default(),
+        step_limit: None,
+    };
+    while let Some(arg) = args.next() {
+        // Accept both `--flag value` and `--flag=value`.
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.into())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} needs a value", flag))
+        };
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-e" | "--eval" => set_source(&mut options.source, Source::Inline(value()?))?,
+            "-i" | "--input" => options.input = Some(value()?.into()),
+            "-t" | "--tape-size" => {
+                options.tape_len = match value()?.parse() {
+                    Ok(0) | Err(_) => return Err("tape size must be a positive number".into()),
+                    Ok(len) => len,
+                }
+            }
+            "-c" | "--cell" => {
+                options.width = match value()?.as_str() {
+                    "8" => Width::U8,
+                    "16" => Width::U16,
+                    "32" => Width::U32,
+                    "64" => Width::U64,
+                    #[cfg(feature = "bignum")]
+                    "big" => Width::Big,
+                    other => return Err(format!("unsupported cell width '{}'", other)),
+                }
+            }
+            "--eof" => {
+                options.eof_policy = match value()?.as_str() {
+                    "unchanged" => EofPolicy::Unchanged,
+                    "zero" => EofPolicy::Zero,
+                    "minus-one" => EofPolicy::MinusOne,
+                    "error" => EofPolicy::Error,
+                    other => return Err(format!("unknown EOF policy '{}'", other)),
+                }
+            }
+            "--pointer" => {
+                options.pointer_mode = match value()?.as_str() {
+                    "wrap" => PointerMode::Wrap,
+                    "error" => PointerMode::Error,
+                    "extend" => PointerMode::Extend,
+                    other => return Err(format!("unknown pointer mode '{}'", other)),
+                }
+            }
+            "--arithmetic" => {
+                options.arithmetic = match value()?.as_str() {
+                    "wrapping" => Arithmetic::Wrapping,
+                    "checked" => Arithmetic::Checked,
+                    other => return Err(format!("unknown arithmetic '{}'", other)),
+                }
+            }
+            "--no-optimize" => options.optimize = false,
+            "--partial-eval" => options.partial_eval = Some(parse_steps(value()?)?),
+            "--step-limit" => options.step_limit = Some(parse_steps(value()?)?),
+            "--backend" => {
+                options.backend = match value()?.as_str() {
+                    "interpreter" => Backend::Interpreter,
+                    #[cfg(feature = "jit")]
+                    "jit" => Backend::Jit,
+                    #[cfg(feature = "cranelift")]
+                    "cranelift" => Backend::Cranelift,
+                    other => return Err(format!("backend '{}' is not available", other)),
+                }
+            }
+            // Standard input is the program's input, so it cannot also hold
+            // the program.
+            "-" => return Err("cannot read the program from standard input".into()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ => set_source(&mut options.source, Source::File(arg.into()))?,
+        }
+    }
+    match command.as_str() {
+        "repl" => Ok(Command::Repl(options)),
+        _ if options.source.is_none() => Err("no program given".into()),
+        "debug" => Ok(Command::Debug(options)),
+        "profile" => Ok(Command::Profile(options)),
+        _ => Ok(Command::Run(options)),
+    }
+}
+
+fn parse_steps(steps: String) -> Result<u64, String> {
+    steps
+        .parse()
+        .map_err(|_| format!("invalid step count '{}'", steps))
+}
+
+fn set_source(source: &mut Option<Source>, new: Source) -> Result<(), String> {
+    match source {
+        Some(_) => Err("more than one program given".into()),
+        None => {
+            *source = Some(new);
+            Ok(())
+        }
+    }
+}
+
+/// Opens the program's input, which is standard input unless a file is given.
+fn open_input(options: &Options) -> Result<Box<dyn Read>, String> {
+    match &options.input {
+        None => Ok(Box::new(io::stdin())),
+        Some(path) => match File::open(path) {
+            Ok(file) => Ok(Box::new(BufReader::new(file))),
+            Err(err) => Err(format!("{}: {}", path.display(), err)),
+        },
+    }
+}
+
+fn run<C: Cell>(builder: VmBuilder<C>, options: &Options) -> ExitCode {
+    let source = options.source.as_ref().expect("run needs a program");
+    let (name, code) = match source.read() {
+        Ok(program) => program,
+        Err(err) => return fail(err, EXIT_USAGE),
+    };
+    let input = match open_input(options) {
+        Ok(input) => input,
+        Err(err) => return fail(err, EXIT_USAGE),
+    };
+    let mut stdout = io::stdout().lock();
+    let mut vm = builder.build(input, &mut stdout);
+    if let Err(err) = vm.compile(&code) {
+        return fail(format!("{}: {}", name, err), EXIT_COMPILE);
+    }
+    let result = vm.run();
+    drop(vm);
+    let _ = stdout.flush();
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => fail(err, EXIT_RUNTIME),
+    }
+}
+
+/// Reports `err` and returns the exit status `code`.
+fn fail(err: impl fmt::Display, code: u8) -> ExitCode {
+    eprintln!("bf: {}", err);
+    ExitCode::from(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        parse_args(args.split_whitespace().map(String::from))
+    }
+
+    fn options(args: &str) -> Options {
+        match parse(args) {
+            Ok(Command::Run(options)) => options,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sources() {
+        let file = Some(Source::File("prog.b".into()));
+        let inline = Some(Source::Inline("+.".into()));
+        assert_eq!(options("run prog.b").source, file);
+        assert_eq!(options("-e +.").source, inline);
+        assert_eq!(options("run --eval=+.").source, inline);
+        assert_eq!(parse("--help"), Ok(Command::Help));
+        assert!(parse("").is_err());
+        assert!(parse("run").is_err());
+        assert!(parse("run a.b b.b").is_err());
+        assert!(parse("-e + c.b").is_err());
+        assert!(parse("walk a.b").is_err());
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let repl = |args| match parse(args) {
+            Ok(Command::Repl(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(repl("repl").source, None);
+        assert_eq!(repl("repl -t 5 prog.b").tape_len, 5);
+        assert!(parse("repl a.b b.b").is_err());
+        assert!(matches!(parse("debug a.b"), Ok(Command::Debug(_))));
+        assert!(parse("debug").is_err());
+        assert!(matches!(parse("profile a.b"), Ok(Command::Profile(_))));
+        assert!(parse("profile").is_err());
+    }
+
+    #[test]
+    fn test_options() {
+        let options = options(
+            "run -i in.txt -t 100 -c 16 --eof=minus-one --pointer extend \
+             --arithmetic checked --no-optimize --partial-eval 50 --step-limit 9 prog.b",
+        );
+        assert_eq!(options.input, Some("in.txt".into()));
+        assert_eq!(options.tape_len, 100);
+        assert_eq!(options.width, Width::U16);
+        assert_eq!(options.eof_policy, EofPolicy::MinusOne);
+        assert_eq!(options.pointer_mode, PointerMode::Extend);
+        assert_eq!(options.arithmetic, Arithmetic::Checked);
+        assert!(!options.optimize);
+        assert_eq!(options.partial_eval, Some(50));
+        assert_eq!(options.step_limit, Some(9));
+        assert_eq!(options.backend, Backend::Interpreter);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(parse("run -t 0 a.b").is_err());
+        assert!(parse("run -c 7 a.b").is_err());
+        assert!(parse("run --eof never a.b").is_err());
+        assert!(parse("run --backend gpu a.b").is_err());
+        assert!(parse("run --frobnicate a.b").is_err());
+        assert!(parse("run a.b --input").is_err());
+        assert!(parse("run -").is_err());
+    }
+}
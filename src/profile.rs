@@ -0,0 +1,179 @@
+//! The report printed by `bf profile`.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::process::ExitCode;
+
+use bf::bf::{Cell, Instruction, Profile, VmBuilder};
+
+use crate::debug::position;
+use crate::{fail, open_input, Options, EXIT_COMPILE, EXIT_RUNTIME, EXIT_USAGE};
+
+/// Number of loops listed in the report.
+const TOP_LOOPS: usize = 10;
+
+pub fn run<C: Cell>(builder: VmBuilder<C>, options: &Options) -> ExitCode {
+    let source = options.source.as_ref().expect("profile needs a program");
+    let (name, code) = match source.read() {
+        Ok(program) => program,
+        Err(err) => return fail(err, EXIT_USAGE),
+    };
+    let input = match open_input(options) {
+        Ok(input) => input,
+        Err(err) => return fail(err, EXIT_USAGE),
+    };
+    // Source positions are only kept for unoptimized programs.
+    let mut vm = builder
+        .optimize(false)
+        .profile(true)
+        .build(input, io::stdout());
+    if let Err(err) = vm.compile(&code) {
+        return fail(format!("{}: {}", name, err), EXIT_COMPILE);
+    }
+    let result = vm.run();
+    let _ = io::stdout().flush();
+    // The report goes to standard error, out of the way of the output.
+    let profile = vm.profile().expect("profiling is enabled");
+    eprint!("{}", report(&code, vm.instructions(), profile));
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => fail(err, EXIT_RUNTIME),
+    }
+}
+
+/// Lists the totals, then the source with the number of steps spent on each
+/// line and a heat map of its commands underneath, then the busiest loops.
+fn report(code: &str, instructions: &[Instruction], profile: &Profile) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{} steps, pointer reached cell {}\n",
+        profile.steps(),
+        profile.max_pointer
+    );
+
+    let positions = profile.positions();
+    let max = positions.iter().map(|&(_, count)| count).max().unwrap_or(0);
+    let mut remaining = positions.iter().copied().peekable();
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for line in code.split('\n') {
+        let mut total = 0;
+        let mut heat = String::new();
+        for (at, c) in line.char_indices() {
+            match remaining.next_if(|&(offset, _)| offset == start + at) {
+                Some((_, count)) => {
+                    total += count;
+                    heat.push(heat_digit(count, max));
+                }
+                None if c == '\t' => heat.push('\t'),
+                None => heat.push(' '),
+            }
+        }
+        let heat = heat.trim_end().to_string();
+        lines.push((line, (!heat.is_empty()).then_some((total, heat))));
+        start += line.len() + 1;
+    }
+    if code.ends_with('\n') {
+        lines.pop();
+    }
+    let width = lines
+        .iter()
+        .filter_map(|(_, heat)| heat.as_ref())
+        .map(|(total, _)| total.to_string().len())
+        .max()
+        .unwrap_or(1);
+    for (line, heat) in lines {
+        match heat {
+            Some((total, heat)) => {
+                push_row(&mut out, &total.to_string(), line, width);
+                push_row(&mut out, "", &heat, width);
+            }
+            None => push_row(&mut out, "", line, width),
+        }
+    }
+
+    let mut loops: Vec<_> = instructions
+        .iter()
+        .enumerate()
+        .filter(|(_, instruction)| matches!(instruction, Instruction::LoopStart(_)))
+        .map(|(index, _)| (profile.loop_iterations[index], index))
+        .filter(|&(iterations, _)| iterations > 0)
+        .collect();
+    loops.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    if !loops.is_empty() {
+        let _ = writeln!(out, "\nbusiest loops:");
+    }
+    for (iterations, index) in loops.into_iter().take(TOP_LOOPS) {
+        let (line, column) = position(code, positions[index].0);
+        let _ = writeln!(
+            out,
+            "  line {}, column {}: {} iterations",
+            line, column, iterations
+        );
+    }
+    out
+}
+
+/// Appends `text` to the report after a gutter of `width` columns.
+fn push_row(out: &mut String, gutter: &str, text: &str, width: usize) {
+    let row = format!("{:>width$} | {}", gutter, text);
+    let _ = writeln!(out, "{}", row.trim_end());
+}
+
+/// Ranks `count` from 1 to 9 on a logarithmic scale up to `max`, with `.` for
+/// commands that never ran.
+fn heat_digit(count: u64, max: u64) -> char {
+    if count == 0 {
+        return '.';
+    }
+    if max <= 1 {
+        return '9';
+    }
+    let rank = 8.0 * (count as f64).ln() / (max as f64).ln();
+    char::from_digit(1 + rank as u32, 10).unwrap_or('9')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heat_digit() {
+        assert_eq!(heat_digit(0, 100), '.');
+        assert_eq!(heat_digit(1, 100), '1');
+        assert_eq!(heat_digit(10, 100), '5');
+        assert_eq!(heat_digit(100, 100), '9');
+        assert_eq!(heat_digit(1, 1), '9');
+    }
+
+    #[test]
+    fn test_report() {
+        let code = "++\n[>+++[>+<-]<-]  loop\n\n>>.";
+        let mut vm = VmBuilder::new()
+            .optimize(false)
+            .profile(true)
+            .build(io::empty(), io::sink());
+        vm.compile(code).unwrap();
+        vm.run().unwrap();
+        let report = report(code, vm.instructions(), vm.profile().unwrap());
+        assert_eq!(
+            report,
+            "\
+52 steps, pointer reached cell 2
+
+ 2 | ++
+   | 11
+47 | [>+++[>+<-]<-]  loop
+   | 14444499999444
+   |
+ 3 | >>.
+   | 111
+
+busiest loops:
+  line 2, column 6: 6 iterations
+  line 2, column 1: 2 iterations
+"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! The interactive session started by `bf repl`.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::process::ExitCode;
+
+use bf::bf::{Cell, CompileError, VirtualMachine, VmBuilder};
+
+use crate::{fail, open_input, Options, EXIT_RUNTIME, EXIT_USAGE};
+
+const HELP: &str = "\
+Each line runs as soon as its brackets are closed, on a tape that is kept
+from one line to the next.
+
+  :tape         Show the cells around the pointer
+  :reset        Zero the tape and move the pointer to the first cell
+  :load FILE    Run FILE on the current tape
+  :help         Show this help
+  :quit         End the session, as does the end of input
+";
+
+/// Number of cells shown on each side of the pointer.
+const WINDOW: usize = 8;
+
+pub fn run<C: Cell>(builder: VmBuilder<C>, options: &Options) -> ExitCode {
+    let input = match open_input(options) {
+        Ok(input) => input,
+        Err(err) => return fail(err, EXIT_USAGE),
+    };
+    let mut vm = builder.build(input, io::stdout());
+    if let Some(source) = &options.source {
+        match source.read() {
+            Ok((name, code)) => execute(&mut vm, &name, &code),
+            Err(err) => return fail(err, EXIT_USAGE),
+        }
+    }
+
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    // Lines entered while a bracket is still open.
+    let mut pending = String::new();
+    loop {
+        if interactive {
+            print!("{}", if pending.is_empty() { "bf> " } else { "... " });
+            let _ = io::stdout().flush();
+        }
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(err) => return fail(err, EXIT_RUNTIME),
+        }
+        if pending.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                if !meta_command(&mut vm, command) {
+                    break;
+                }
+                continue;
+            }
+        }
+        pending.push_str(&line);
+        match vm.compile_keeping_tape(&pending) {
+            Err(CompileError::UnmatchedOpen { .. }) => continue,
+            Err(err) => eprintln!("error: {}", err),
+            Ok(()) => report(vm.run()),
+        }
+        pending.clear();
+    }
+    if !pending.is_empty() {
+        eprintln!("error: unmatched '[' at end of input");
+    }
+    ExitCode::SUCCESS
+}
+
+/// Runs a meta-command, returning whether the session goes on.
+fn meta_command<R: Read, W: Write, C: Cell>(
+    vm: &mut VirtualMachine<R, W, C>,
+    command: &str,
+) -> bool {
+    let (name, argument) = match command.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (command, ""),
+    };
+    match (name, argument) {
+        ("tape", "") => print!("{}", tape_window(vm.memory(), vm.pointer())),
+        ("reset", "") => vm.reset(),
+        ("load", "") => eprintln!("error: :load needs a file"),
+        ("load", path) => match std::fs::read_to_string(path) {
+            Ok(code) => execute(vm, path, &code),
+            Err(err) => eprintln!("error: {}: {}", path, err),
+        },
+        ("help", "") => print!("{}", HELP),
+        ("quit" | "q", "") => return false,
+        _ => eprintln!("error: unknown command ':{}', see :help", command),
+    }
+    true
+}
+
+/// Runs `code` from the current state of the tape.
+fn execute<R: Read, W: Write, C: Cell>(vm: &mut VirtualMachine<R, W, C>, name: &str, code: &str) {
+    match vm.compile_keeping_tape(code) {
+        Ok(()) => report(vm.run()),
+        Err(err) => eprintln!("error: {}: {}", name, err),
+    }
+}
+
+fn report<T>(result: Result<T, impl std::fmt::Display>) {
+    let _ = io::stdout().flush();
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+    }
+}
+
+/// Describes the cells around `pointer`, marking the current one.
+pub fn tape_window<C: Cell>(memory: &[C], pointer: usize) -> String {
+    let start = pointer.saturating_sub(WINDOW);
+    let end = memory.len().min(pointer + WINDOW + 1);
+    let mut window = format!("pointer {}, cells {} to {}:\n", pointer, start, end - 1);
+    for (index, cell) in memory[start..end].iter().enumerate() {
+        let separator = if index == 0 { "" } else { " " };
+        if start + index == pointer {
+            let _ = write!(window, "{}[{:?}]", separator, cell);
+        } else {
+            let _ = write!(window, "{}{:?}", separator, cell);
+        }
+    }
+    window.push('\n');
+    window
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tape_window() {
+        let memory = [1u8, 2, 3];
+        assert_eq!(
+            tape_window(&memory, 1),
+            "pointer 1, cells 0 to 2:\n1 [2] 3\n"
+        );
+        let memory = [0u16; 100];
+        assert_eq!(
+            tape_window(&memory, 50),
+            "pointer 50, cells 42 to 58:\n0 0 0 0 0 0 0 0 [0] 0 0 0 0 0 0 0 0\n"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn bf(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bf"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_inline() {
+    let output = bf(&["-e", ",[.[-],]"], "hello");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"hello");
+}
+
+#[test]
+fn test_files() {
+    let dir = std::env::temp_dir().join(format!("bf-cli-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("rev.b");
+    let input = dir.join("input.txt");
+    fs::write(&program, ">,[>,]<[.<]").unwrap();
+    fs::write(&input, "abc").unwrap();
+    let output = bf(
+        &[
+            "run",
+            "--eof",
+            "zero",
+            "--input",
+            input.to_str().unwrap(),
+            program.to_str().unwrap(),
+        ],
+        "",
+    );
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"cba");
+}
+
+#[test]
+fn test_exit_codes() {
+    assert_eq!(bf(&["-e", "+[", "--cell", "16"], "").status.code(), Some(3));
+    assert_eq!(
+        bf(&["-e", "<", "--pointer", "error"], "").status.code(),
+        Some(1)
+    );
+    assert_eq!(
+        bf(&["-e", "-", "--arithmetic", "checked"], "")
+            .status
+            .code(),
+        Some(1)
+    );
+    assert_eq!(
+        bf(&["-e", ",", "--eof", "error"], "").status.code(),
+        Some(1)
+    );
+    assert_eq!(
+        bf(&["-e", "+[]", "--step-limit", "1000"], "").status.code(),
+        Some(1)
+    );
+    assert_eq!(
+        bf(&["run", "/nonexistent/prog.b"], "").status.code(),
+        Some(2)
+    );
+    assert_eq!(
+        bf(&["run", "-t", "0", "-e", "+"], "").status.code(),
+        Some(2)
+    );
+    assert_eq!(bf(&["--help"], "").status.code(), Some(0));
+}
+
+#[test]
+fn test_cell_width() {
+    // 256 increments overflow an 8-bit cell but not a 16-bit one.
+    let code = "++++++++[>++++++++[>++++<-]<-]>>[[-]+++++++++++++++++++++++++++++++++.[-]]";
+    assert_eq!(bf(&["-e", code], "").stdout, b"");
+    assert_eq!(bf(&["-e", code, "-c", "16"], "").stdout, b"!");
+}
+
+#[test]
+fn test_repl() {
+    let output = bf(
+        &["repl", "-t", "4"],
+        "+++>++\n:tape\n<[.-\n]\n]\n:reset\n>,.\nA\n:tape\n:bogus\n:load /nonexistent.b\n[",
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "pointer 1, cells 0 to 3:\n3 [2] 0 0\n\
+         \x03\x02\x01\
+         A\
+         pointer 1, cells 0 to 3:\n0 [65] 0 0\n"
+    );
+    let errors = String::from_utf8(output.stderr).unwrap();
+    assert!(errors.contains("unmatched ']'"), "{}", errors);
+    assert!(errors.contains("unknown command ':bogus'"), "{}", errors);
+    assert!(errors.contains("/nonexistent.b: "), "{}", errors);
+    assert!(
+        errors.contains("unmatched '[' at end of input"),
+        "{}",
+        errors
+    );
+}
+
+#[test]
+fn test_debug() {
+    let output = bf(
+        &["debug", "-e", "++[->+<#]>."],
+        "break 1:6\ncontinue\n\nwatch 1\nc\nnext\nd\nc\nstep\nbogus\n",
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stops: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.starts_with("line "))
+        .collect();
+    assert_eq!(
+        stops,
+        [
+            "line 1, column 1:",
+            "line 1, column 6:",
+            "line 1, column 6:",
+            "line 1, column 7:",
+            "line 1, column 9:",
+        ]
+    );
+    // The `#` dumps the tape on the way to the second breakpoint.
+    assert!(
+        stdout.contains("pointer 0, cells 0 to 8:\n[1] 1 0"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("cell 1 changed\n"), "{}", stdout);
+    assert!(
+        stdout.ends_with("\x02program ended\nprogram ended\n"),
+        "{}",
+        stdout
+    );
+    let errors = String::from_utf8(output.stderr).unwrap();
+    assert!(errors.contains("unknown command 'bogus'"), "{}", errors);
+    assert_eq!(bf(&["debug", "-e", "]"], "").status.code(), Some(3));
+}
+
+#[test]
+fn test_profile() {
+    let output = bf(&["profile", "-e", "+++[>++<-]>."], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\x06");
+    let report = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        report.starts_with("24 steps, pointer reached cell 1\n"),
+        "{}",
+        report
+    );
+    assert!(
+        report.contains("line 1, column 4: 3 iterations"),
+        "{}",
+        report
+    );
+
+    // The report is still printed when the program fails.
+    let output = bf(&["profile", "--step-limit", "2", "-e", "+[]"], "");
+    assert_eq!(output.status.code(), Some(1));
+    let report = String::from_utf8(output.stderr).unwrap();
+    assert!(report.starts_with("2 steps"), "{}", report);
+}